@@ -0,0 +1,105 @@
+//! Throughput of the 1DEX `calc_out_given_in` across weight ratio regimes, alone and within a full quote.
+//! Run with `cargo bench --bench one_intro_calc`. A fixed-point implementation should be added as a second
+//! function in the same group so both report ns/iter side by side.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use jupiter_amm_interface::SwapMode;
+use jupiter_core::amms::{
+    one_intro::math::{calc_out_given_in, calc_out_given_in_constant_product, Balance, FeeRate, Weight, PONE},
+    one_intro_amm::quote_from_state,
+    one_intro_state::{PoolState, TokenRecord, EMPTY_TOKEN_MINT, MAX_TOKEN_COUNT},
+};
+use solana_sdk::pubkey::Pubkey;
+
+fn record(balance: u64, weight: u64) -> TokenRecord {
+    TokenRecord {
+        mint_key: Pubkey::new_unique(),
+        account_key: Pubkey::new_unique(),
+        balance,
+        weight,
+    }
+}
+
+fn pool_state(token_in: TokenRecord, token_out: TokenRecord) -> PoolState {
+    let mut pool_token_array = [TokenRecord {
+        mint_key: EMPTY_TOKEN_MINT,
+        account_key: EMPTY_TOKEN_MINT,
+        balance: 0,
+        weight: 0,
+    }; MAX_TOKEN_COUNT];
+    pool_token_array[0] = token_in;
+    pool_token_array[1] = token_out;
+
+    PoolState {
+        pool_auth_pda_key: Pubkey::new_unique(),
+        pool_auth_pda_bump: 255,
+        pool_lp_mint_key: Pubkey::new_unique(),
+        pool_lp_virtual_supply: 1_000_000_000,
+        pool_token_count: 2,
+        pool_token_array,
+        pool_token_total_weight: token_in.weight + token_out.weight,
+        pool_swap_fee_ratio: 3_000_000,
+    }
+}
+
+fn bench_calc_out_given_in(c: &mut Criterion) {
+    // (label, weight in, weight out), the extreme regimes take the `pow_minus_one` path
+    let regimes = [
+        ("50_50", PONE / 2, PONE / 2),
+        ("80_20", 8 * PONE / 10, 2 * PONE / 10),
+        ("20_80", 2 * PONE / 10, 8 * PONE / 10),
+        ("98_2", 98 * PONE / 100, 2 * PONE / 100),
+        ("2_98", 2 * PONE / 100, 98 * PONE / 100),
+    ];
+
+    let mut group = c.benchmark_group("calc_out_given_in");
+    for (label, weight_in, weight_out) in regimes {
+        let token_in = record(10_000_000_000, weight_in);
+        let token_out = record(20_000_000_000, weight_out);
+        let state = pool_state(token_in, token_out);
+
+        group.bench_function(BenchmarkId::new("f64", label), |b| {
+            b.iter(|| {
+                calc_out_given_in(
+                    black_box(Balance(token_in.balance)),
+                    black_box(Weight(weight_in)),
+                    black_box(Balance(token_out.balance)),
+                    black_box(Weight(weight_out)),
+                    black_box(100_000_000),
+                    FeeRate::default(),
+                    FeeRate::default(),
+                )
+                .unwrap()
+            })
+        });
+        if weight_in == weight_out {
+            group.bench_function(BenchmarkId::new("constant_product", label), |b| {
+                b.iter(|| {
+                    calc_out_given_in_constant_product(
+                        black_box(Balance(token_in.balance)),
+                        black_box(Balance(token_out.balance)),
+                        black_box(100_000_000),
+                        FeeRate::default(),
+                    )
+                    .unwrap()
+                })
+            });
+        }
+        group.bench_with_input(BenchmarkId::new("f64_quote", label), &state, |b, state| {
+            b.iter(|| {
+                quote_from_state(
+                    black_box(state),
+                    token_in.mint_key,
+                    token_out.mint_key,
+                    black_box(100_000_000),
+                    SwapMode::ExactIn,
+                )
+                .unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_calc_out_given_in);
+criterion_main!(benches);
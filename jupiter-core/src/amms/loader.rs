@@ -1,10 +1,42 @@
-use std::collections::HashSet;
+use std::{collections::{HashMap, HashSet}, sync::RwLock};
 
 use anyhow::{anyhow, Result};
 use jupiter_amm_interface::{Amm, KeyedAccount};
+use lazy_static::lazy_static;
 use solana_sdk::pubkey::Pubkey;
 
-use super::{one_intro_amm::{OneIntroAmm, ONE_INTRO_PROGRAM_ID}, spl_token_swap_amm::{SplTokenSwapAmm, SPL_TOKEN_SWAP_PROGRAMS}};
+#[cfg(feature = "spl-token-swap")]
+use super::spl_token_swap_amm::{SplTokenSwapAmm, SPL_TOKEN_SWAP_PROGRAMS};
+use super::{one_intro_amm::{OneIntroAmm, ONE_INTRO_PROGRAM_ID}, one_intro_state::POOL_STATE_DISCRIMINATOR};
+
+/// Builds an AMM from an account owned by the program it was registered for
+pub type AmmConstructor = fn(&KeyedAccount) -> Result<Box<dyn Amm + Send + Sync>>;
+
+/// AMM constructors by owner program, for AMMs defined outside this crate
+#[derive(Clone, Debug, Default)]
+pub struct AmmRegistry {
+    constructors: HashMap<Pubkey, AmmConstructor>,
+}
+
+impl AmmRegistry {
+    /// Registers `constructor` for accounts owned by `owner`, replacing any previous one
+    pub fn register(&mut self, owner: Pubkey, constructor: AmmConstructor) -> Option<AmmConstructor> {
+        self.constructors.insert(owner, constructor)
+    }
+
+    pub fn get(&self, owner: &Pubkey) -> Option<AmmConstructor> {
+        self.constructors.get(owner).copied()
+    }
+}
+
+lazy_static! {
+    static ref AMM_REGISTRY: RwLock<AmmRegistry> = RwLock::new(AmmRegistry::default());
+}
+
+/// Registers `constructor` with the registry `amm_factory` consults for owners it does not support itself
+pub fn register_amm(owner: Pubkey, constructor: AmmConstructor) -> Option<AmmConstructor> {
+    AMM_REGISTRY.write().unwrap_or_else(|v| v.into_inner()).register(owner, constructor)
+}
 
 pub fn amm_factory(
     keyed_account: &KeyedAccount,
@@ -13,14 +45,20 @@ pub fn amm_factory(
     let owner = keyed_account.account.owner;
 
     // Add your AMM here
+    #[cfg(feature = "spl-token-swap")]
     if SPL_TOKEN_SWAP_PROGRAMS.contains_key(&owner) {
-        Ok(Box::new(SplTokenSwapAmm::from_keyed_account(
+        return Ok(Box::new(SplTokenSwapAmm::from_keyed_account(
             keyed_account,
-        )?))
-    } else if owner.eq(&ONE_INTRO_PROGRAM_ID) {
+        )?));
+    }
+
+    // The 1DEX program also owns its metadata state, only pool states are AMMs.
+    if owner.eq(&ONE_INTRO_PROGRAM_ID) && keyed_account.account.data.starts_with(&POOL_STATE_DISCRIMINATOR) {
         Ok(Box::new(OneIntroAmm::from_keyed_account(
             keyed_account,
         )?))
+    } else if let Some(constructor) = AMM_REGISTRY.read().unwrap_or_else(|v| v.into_inner()).get(&owner) {
+        constructor(keyed_account)
     } else {
         Err(anyhow!(
             "Unsupported pool {}, from owner {}",
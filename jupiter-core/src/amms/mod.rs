@@ -1,9 +1,14 @@
+#[cfg(feature = "spl-token-swap")]
 mod account_meta_from_token_swap;
 pub mod amm;
+#[cfg(feature = "spl-token-swap")]
 pub mod spl_token_swap_amm;
 mod one_intro_calc;
-mod one_intro_state;
+pub mod one_intro;
+pub mod one_intro_state;
 pub mod one_intro_amm;
+#[cfg(feature = "test-utils")]
+pub mod one_intro_test_utils;
 pub mod test_harness;
 
 pub mod loader;
@@ -0,0 +1,25 @@
+/// The 1DEX weighted pool math, for reuse without an `OneIntroAmm`.
+///
+/// Balances and amounts are raw token units. Weights only enter as a ratio, so any scale works, the pool
+/// stores them relative to `pool_token_total_weight`. Fees and ratios such as `MAX_IN_RATIO` are relative to
+/// `PONE`, `3_000_000` being 0.3%.
+///
+/// Balances, weights and fees are distinct types, so swapping two arguments does not compile:
+///
+/// ```compile_fail
+/// use jupiter_core::amms::one_intro::math::{calc_out_given_in, Balance, FeeRate, Weight};
+/// calc_out_given_in(Weight(1), Balance(2), Balance(3), Weight(4), 5, FeeRate(0), FeeRate(0));
+/// ```
+///
+/// ```compile_fail
+/// use jupiter_core::amms::one_intro::math::{Balance, Weight};
+/// let balance: Balance = Weight(500_000_000);
+/// ```
+pub mod math {
+    pub use crate::amms::one_intro_calc::{
+        calc_in_given_out, calc_out_given_in, calc_out_given_in_constant_product, proportional, proportional_ceil, value_from_shares, Balance, ErrorCode,
+        FeeRate, RoundDirection, Weight, MAX_IN_RATIO, MAX_OUT_RATIO, MAX_PRECISE_BALANCE, PONE,
+    };
+
+    pub use crate::amms::one_intro_calc::safemath::{exp, exp_m1, f64_to_u64_rounded, ln, ln_1p, pow, pow_minus_one};
+}
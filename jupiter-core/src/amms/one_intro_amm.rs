@@ -1,22 +1,355 @@
-use anchor_lang::AnchorDeserialize;
-use anyhow::{Context, Result};
+use std::{collections::HashMap, sync::Mutex, time::SystemTime};
+
+use anchor_lang::{prelude::borsh, AnchorDeserialize, AnchorSerialize};
+use anyhow::{anyhow, Context, Result};
 use itertools::Itertools;
 use jupiter_amm_interface::{AccountMap, Amm, KeyedAccount, Quote, QuoteParams, Swap, SwapAndAccountMetas, SwapMode, SwapParams};
-use rust_decimal::Decimal;
-use solana_sdk::{instruction::AccountMeta, pubkey, pubkey::Pubkey};
-use spl_associated_token_account::get_associated_token_address;
+use rust_decimal::{prelude::ToPrimitive, Decimal};
+use serde::Serialize;
+use solana_sdk::{instruction::{AccountMeta, Instruction}, pubkey, pubkey::Pubkey};
+use spl_associated_token_account::get_associated_token_address_with_program_id;
+use spl_token_2022::{extension::StateWithExtensions, state::Mint};
+
+use super::{one_intro_calc::{safemath::pow_minus_one, calc_in_given_out, calc_out_given_in, calc_out_given_in_constant_product, proportional, proportional_ceil, value_from_shares, MAX_IN_RATIO, MAX_OUT_RATIO, PONE}, one_intro_state::{MetadataState, PoolState, TokenRecord, EMPTY_TOKEN_MINT, MAX_TOKEN_COUNT, METADATA_STATE_DISCRIMINATOR}};
 
-use super::{one_intro_calc::{calc_in_given_out, calc_out_given_in, value_from_shares, MAX_IN_RATIO, MAX_OUT_RATIO, PONE}, one_intro_state::PoolState};
+pub use super::one_intro_calc::{Balance, ErrorCode, FeeRate, Weight, MAX_PRECISE_BALANCE};
 
 pub const ONE_INTRO_PROGRAM_ID: Pubkey = pubkey!("DEXYosS6oEGvk8uCDayvwEZz4qEyDJRf9nFgYCaqPMTm");
 
-const ONE_INTRO_METADATA_STATE: Pubkey = pubkey!("5nmAbnjJfW1skrPvYjLTBNdhoKzJfznnbvDcM8G2U7Ki");
+pub const ONE_INTRO_METADATA_STATE: Pubkey = pubkey!("5nmAbnjJfW1skrPvYjLTBNdhoKzJfznnbvDcM8G2U7Ki");
 const ONE_INTRO_TOKEN_AUTH_PDA: Pubkey = pubkey!("ATowQwFzdJBJ9VFSfoNKmuB8GiSeo8foM5vRriwmKmFB");
 
+// The pool auth PDA is derived from this seed and the pool state key, with the bump stored in the pool state.
+pub const POOL_AUTH_PDA_SEED: &[u8] = b"pool_auth_pda_seed";
+
+// sha256("global:swap")[..8], the Anchor discriminator of the swap instruction.
+pub const SWAP_INSTRUCTION_DISCRIMINATOR: [u8; 8] = [248, 198, 158, 145, 225, 117, 135, 200];
+
+// Estimated compute units of the swap instruction, not measured limits.
+// The program loops over every pool token, and Token-2022 transfers pay for the extension checks.
+pub const SWAP_BASE_COMPUTE_UNITS: u32 = 40_000;
+pub const SWAP_COMPUTE_UNITS_PER_POOL_TOKEN: u32 = 5_000;
+pub const TOKEN_2022_TRANSFER_COMPUTE_UNITS: u32 = 10_000;
+
+// `break_even_in` checks at most this many output amounts, each for a few quotes. Break-even is typically
+// reached within about `1 / fee` input units, a few hundred for common fee tiers.
+const BREAK_EVEN_SCAN_LIMIT: u64 = 1_000;
+
+#[derive(AnchorSerialize, AnchorDeserialize, PartialEq, Debug, Clone, Copy)]
+pub struct SwapInstructionArgs {
+    pub token_in_amount: u64,
+    pub minimum_token_out_amount: u64,
+}
+
+impl SwapInstructionArgs {
+    pub fn data(&self) -> Vec<u8> {
+        let mut data = SWAP_INSTRUCTION_DISCRIMINATOR.to_vec();
+        data.extend(self.try_to_vec().expect("Serializing u64 args cannot fail"));
+        data
+    }
+}
+
+/// What an integrator knows changed on chain since the last update
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AccountUpdateHint {
+    /// Nothing is known, refresh every account
+    All,
+    /// Only token balances moved, e.g. after a swap, which are tracked in the pool state
+    BalancesOnly,
+}
+
+/// How quotes are adjusted to account for rounding differences with the on-chain program
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum RoundingPolicy {
+    /// Quote exactly what the off-chain math computes
+    #[default]
+    Exact,
+    /// Take `epsilon` off ExactIn outputs and add it to ExactOut inputs, so quotes never beat on-chain results
+    Conservative { epsilon: u64 },
+    /// Add `epsilon` to ExactIn outputs and take it off ExactOut inputs
+    Optimistic { epsilon: u64 },
+}
+
+impl RoundingPolicy {
+    fn apply(&self, mut quote: Quote, swap_mode: SwapMode) -> Quote {
+        match (self, swap_mode) {
+            (RoundingPolicy::Exact, _) => {},
+            (RoundingPolicy::Conservative { epsilon }, SwapMode::ExactIn) => {
+                quote.out_amount = quote.out_amount.saturating_sub(*epsilon);
+            },
+            (RoundingPolicy::Conservative { epsilon }, SwapMode::ExactOut) => {
+                quote.in_amount = quote.in_amount.saturating_add(*epsilon);
+            },
+            (RoundingPolicy::Optimistic { epsilon }, SwapMode::ExactIn) => {
+                quote.out_amount = quote.out_amount.saturating_add(*epsilon);
+            },
+            (RoundingPolicy::Optimistic { epsilon }, SwapMode::ExactOut) => {
+                quote.in_amount = quote.in_amount.saturating_sub(*epsilon);
+            },
+        }
+        quote
+    }
+}
+
+/// A step the user transaction needs around the swap when one side is native SOL held as wSOL
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WrappedSolStep {
+    /// Before the swap, create a temporary wSOL token account owned by the user
+    CreateTemporaryAccount,
+    /// Before the swap, transfer `lamports` into the temporary account and `SyncNative` it
+    Wrap { lamports: u64 },
+    /// After the swap, close the temporary account, returning its SOL to the user
+    CloseTemporaryAccount,
+}
+
+/// The direction of a swap relative to the pool token order, A being the first pool token and B the second
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SwapDirection {
+    AToB,
+    BToA,
+}
+
+/// How `token_transfer_authority` authorizes the transfer out of the user token account
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TransferAuthority {
+    /// A signer of the transaction, as `get_swap_and_account_metas` assumes
+    #[default]
+    Signer,
+    /// A delegate such as a PDA the calling program signs for in its CPI, not a signer of the transaction
+    Delegate,
+}
+
+/// The unit `pool_swap_fee_ratio` is stored in, converted to a `PONE` relative `FeeRate` before quoting
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FeeUnit {
+    /// Relative to `PONE`, as the deployed program stores it
+    #[default]
+    Pone,
+    /// Basis points, `30` being 0.3%
+    Bps,
+}
+
+impl FeeUnit {
+    /// Errors unless the converted fee is below 100%, the raw ratio alone does not bound it for `FeeUnit::Bps`
+    pub fn fee_rate(&self, pool_swap_fee_ratio: u64) -> Result<FeeRate> {
+        let fee_rate = match self {
+            FeeUnit::Pone => Some(pool_swap_fee_ratio),
+            FeeUnit::Bps => pool_swap_fee_ratio.checked_mul(PONE / 10_000),
+        };
+        match fee_rate {
+            Some(fee_rate) if fee_rate < PONE => Ok(FeeRate(fee_rate)),
+            _ => Err(anyhow!("Swap fee ratio {} in {:?} is not below 100%", pool_swap_fee_ratio, self)),
+        }
+    }
+}
+
+/// A quote whose `fee_amount` is the total fee, of which `referrer_fee_amount` goes to the referrer
+#[derive(Clone, Copy, Debug)]
+pub struct ReferredQuote {
+    pub quote: Quote,
+    pub referrer_fee_amount: u64,
+}
+
+impl ReferredQuote {
+    /// The part of the fee the pool retains, zero if `referrer_fee_amount` was set above the whole fee
+    pub fn pool_fee_amount(&self) -> u64 {
+        self.quote.fee_amount.saturating_sub(self.referrer_fee_amount)
+    }
+}
+
+/// A quote whose `fee_amount` is the total fee, of which `protocol_fee_amount` is the metadata state protocol fee
+#[derive(Clone, Copy, Debug)]
+pub struct QuoteFeeComponents {
+    pub quote: Quote,
+    pub protocol_fee_amount: u64,
+}
+
+impl QuoteFeeComponents {
+    /// The part of the fee charged at the pool swap fee rate, zero if `protocol_fee_amount` was set above the whole fee
+    pub fn swap_fee_amount(&self) -> u64 {
+        self.quote.fee_amount.saturating_sub(self.protocol_fee_amount)
+    }
+}
+
+/// The amounts of a quote computed from raw balances and weights, without mints to attach them to
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct RawQuote {
+    pub in_amount: u64,
+    pub out_amount: u64,
+    /// Charged on the input, like `Quote::fee_amount`
+    pub fee_amount: u64,
+    pub not_enough_liquidity: bool,
+}
+
+type QuoteCacheKey = (Pubkey, Pubkey, u64, bool);
+
+/// Memoized quotes of the current pool state, cleared whenever the state changes
+struct QuoteCache {
+    capacity: usize,
+    quotes: Mutex<HashMap<QuoteCacheKey, Quote>>,
+}
+
+impl QuoteCache {
+    fn new(capacity: usize) -> Self {
+        QuoteCache {
+            capacity,
+            quotes: Mutex::new(HashMap::with_capacity(capacity)),
+        }
+    }
+
+    fn key(quote_params: &QuoteParams) -> QuoteCacheKey {
+        (
+            quote_params.input_mint,
+            quote_params.output_mint,
+            quote_params.amount,
+            match quote_params.swap_mode {
+                SwapMode::ExactIn => true,
+                SwapMode::ExactOut => false,
+            },
+        )
+    }
+
+    fn get(&self, key: &QuoteCacheKey) -> Option<Quote> {
+        self.quotes.lock().unwrap().get(key).copied()
+    }
+
+    fn insert(&self, key: QuoteCacheKey, quote: Quote) {
+        let mut quotes = self.quotes.lock().unwrap();
+        if quotes.len() >= self.capacity {
+            quotes.clear();
+        }
+        quotes.insert(key, quote);
+    }
+
+    fn len(&self) -> usize {
+        self.quotes.lock().unwrap().len()
+    }
+
+    fn clear(&self) {
+        self.quotes.lock().unwrap().clear();
+    }
+}
+
 pub struct OneIntroAmm {
     key: Pubkey,
     program_id: Pubkey,
     state: PoolState,
+    quote_cache: Option<QuoteCache>,
+    rounding_policy: RoundingPolicy,
+    fee_unit: FeeUnit,
+    // `pool_swap_fee_ratio` converted from `fee_unit`, validated whenever either changes.
+    pool_fee_rate: FeeRate,
+    exit_fee_rate: FeeRate,
+    // `None` unless the metadata state is loaded, see `with_metadata_state`.
+    protocol_fee_rate: Option<FeeRate>,
+    // `None` unless the mint accounts are loaded, see `with_mint_decimals`.
+    mint_decimals: Option<HashMap<Pubkey, u8>>,
+    max_price_impact: Option<Decimal>,
+    // The token accounts loaded on update with their mint, `None` until one is found, see `with_token_accounts`.
+    token_account_mints: HashMap<Pubkey, Option<Pubkey>>,
+    // The program every pool token transfer goes through, taken from the mint owners when they are loaded.
+    token_program_id: Pubkey,
+    // `get_reserve_mints` is called on every routing pass, so it is only recomputed on update.
+    reserve_mints: Vec<Pubkey>,
+    last_updated: Option<SystemTime>,
+    // The accounts other than the pool state that the last update was missing, their values are stale.
+    stale_accounts: Vec<Pubkey>,
+}
+
+/// A uniform snapshot of a pool for monitoring, serializable for scraping
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct PoolMetrics {
+    pub token_count: u64,
+    pub total_weight: u64,
+    /// Relative to `PONE`, including the protocol fee when the metadata state is loaded
+    pub fee_rate: u64,
+    /// The active tokens, in pool token array order
+    pub tokens: Vec<TokenMetrics>,
+    pub lp_virtual_supply: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct TokenMetrics {
+    pub mint: Pubkey,
+    pub balance: u64,
+    pub weight: u64,
+}
+
+// What `snapshot` persists, the rest of the AMM is either configuration or derived from the state.
+#[derive(AnchorSerialize, AnchorDeserialize)]
+struct AmmSnapshot {
+    key: Pubkey,
+    program_id: Pubkey,
+    state: PoolState,
+}
+
+/// A borrowed, read-only view of a pool state, to quote repeatedly without cloning the AMM
+#[derive(Clone, Copy, Debug)]
+pub struct PoolQuoter<'a> {
+    state: &'a PoolState,
+    rounding_policy: RoundingPolicy,
+    fee_unit: FeeUnit,
+    exit_fee_rate: FeeRate,
+    protocol_fee_rate: FeeRate,
+    swap_fee_override: Option<FeeRate>,
+}
+
+impl<'a> PoolQuoter<'a> {
+    pub fn new(state: &'a PoolState) -> Self {
+        PoolQuoter {
+            state,
+            rounding_policy: RoundingPolicy::default(),
+            fee_unit: FeeUnit::default(),
+            exit_fee_rate: FeeRate::default(),
+            protocol_fee_rate: FeeRate::default(),
+            swap_fee_override: None,
+        }
+    }
+
+    pub fn with_rounding_policy(mut self, rounding_policy: RoundingPolicy) -> Self {
+        self.rounding_policy = rounding_policy;
+        self
+    }
+
+    pub fn with_fee_unit(mut self, fee_unit: FeeUnit) -> Self {
+        self.fee_unit = fee_unit;
+        self
+    }
+
+    pub fn with_exit_fee_rate(mut self, exit_fee_rate: FeeRate) -> Self {
+        self.exit_fee_rate = exit_fee_rate;
+        self
+    }
+
+    /// Adds `protocol_fee_rate` to the pool swap fee
+    pub fn with_protocol_fee_rate(mut self, protocol_fee_rate: FeeRate) -> Self {
+        self.protocol_fee_rate = protocol_fee_rate;
+        self
+    }
+
+    /// Quotes with `swap_fee_rate` in place of the pool `pool_swap_fee_ratio`, the state is left untouched
+    pub fn with_swap_fee_override(mut self, swap_fee_rate: FeeRate) -> Self {
+        self.swap_fee_override = Some(swap_fee_rate);
+        self
+    }
+
+    pub fn quote(&self, quote_params: &QuoteParams) -> Result<Quote> {
+        let pool_fee_rate = match self.swap_fee_override {
+            Some(swap_fee_rate) => swap_fee_rate,
+            None => self.fee_unit.fee_rate(self.state.pool_swap_fee_ratio)?,
+        };
+        let quote = quote_state(
+            self.state,
+            quote_params.input_mint,
+            quote_params.output_mint,
+            quote_params.amount,
+            quote_params.swap_mode,
+            combined_fee_rate(pool_fee_rate, self.protocol_fee_rate),
+            self.exit_fee_rate,
+        )?;
+
+        Ok(self.rounding_policy.apply(quote, quote_params.swap_mode))
+    }
 }
 
 impl Clone for OneIntroAmm {
@@ -25,10 +358,820 @@ impl Clone for OneIntroAmm {
             key: self.key,
             program_id: self.program_id,
             state: self.state,
+            quote_cache: self.quote_cache.as_ref().map(|v| QuoteCache::new(v.capacity)),
+            rounding_policy: self.rounding_policy,
+            fee_unit: self.fee_unit,
+            pool_fee_rate: self.pool_fee_rate,
+            exit_fee_rate: self.exit_fee_rate,
+            protocol_fee_rate: self.protocol_fee_rate,
+            mint_decimals: self.mint_decimals.clone(),
+            max_price_impact: self.max_price_impact,
+            token_account_mints: self.token_account_mints.clone(),
+            token_program_id: self.token_program_id,
+            reserve_mints: self.reserve_mints.clone(),
+            last_updated: self.last_updated,
+            stale_accounts: self.stale_accounts.clone(),
         }
     }
 }
 
+impl OneIntroAmm {
+    /// Memoizes up to `capacity` quotes between updates
+    pub fn with_quote_cache(mut self, capacity: usize) -> Self {
+        self.quote_cache = Some(QuoteCache::new(capacity.max(1)));
+        self
+    }
+
+    pub fn with_rounding_policy(mut self, rounding_policy: RoundingPolicy) -> Self {
+        self.rounding_policy = rounding_policy;
+        self
+    }
+
+    /// The unit the pool stores `pool_swap_fee_ratio` in, `FeeUnit::Pone` by default.
+    /// Errors if the current fee converts to 100% or more in `fee_unit`.
+    pub fn with_fee_unit(mut self, fee_unit: FeeUnit) -> Result<Self> {
+        self.pool_fee_rate = fee_unit.fee_rate(self.state.pool_swap_fee_ratio)?;
+        self.fee_unit = fee_unit;
+        Ok(self)
+    }
+
+    /// Charges `exit_fee_rate` on the output side in addition to the pool swap fee on the input, zero by default
+    pub fn with_exit_fee_rate(mut self, exit_fee_rate: FeeRate) -> Self {
+        self.exit_fee_rate = exit_fee_rate;
+        self
+    }
+
+    /// Also loads the global metadata state on update, adding its protocol fee to the pool swap fee
+    pub fn with_metadata_state(mut self) -> Self {
+        self.protocol_fee_rate = Some(self.protocol_fee_rate.unwrap_or_default());
+        self
+    }
+
+    /// The protocol fee of the metadata state, `None` if it is not loaded
+    pub fn protocol_fee_rate(&self) -> Option<FeeRate> {
+        self.protocol_fee_rate
+    }
+
+    pub fn metrics(&self) -> PoolMetrics {
+        PoolMetrics {
+            token_count: self.state.pool_token_count,
+            total_weight: self.state.pool_token_total_weight,
+            fee_rate: self.fee_rate().0,
+            tokens: self.state.active_token_records()
+                .map(|v| TokenMetrics {
+                    mint: v.mint_key,
+                    balance: v.balance,
+                    weight: v.weight,
+                })
+                .collect_vec(),
+            lp_virtual_supply: self.state.pool_lp_virtual_supply,
+        }
+    }
+
+    /// The key, program id and pool state, serialized to persist a warm pool across restarts
+    pub fn snapshot(&self) -> Vec<u8> {
+        AmmSnapshot {
+            key: self.key,
+            program_id: self.program_id,
+            state: self.state,
+        }
+        .try_to_vec()
+        .expect("Serializing a snapshot cannot fail")
+    }
+
+    /// Rebuilds an AMM from `snapshot` bytes, which must have been taken of the pool `key` owned by `program_id`.
+    /// Builder configuration is not part of the snapshot and starts from the defaults.
+    pub fn restore(key: Pubkey, program_id: Pubkey, bytes: &[u8]) -> Result<OneIntroAmm> {
+        let snapshot = AmmSnapshot::deserialize(&mut &bytes[..]).context("Invalid snapshot")?;
+        if snapshot.key != key || snapshot.program_id != program_id {
+            return Err(anyhow!(
+                "Snapshot of pool {} owned by {} does not match pool {} owned by {}",
+                snapshot.key,
+                snapshot.program_id,
+                key,
+                program_id
+            ));
+        }
+
+        Ok(OneIntroAmm::new(key, program_id, validate_pool_state(snapshot.state)?))
+    }
+
+    /// Also loads the reserve mint accounts on update, for `ui_amount`. Quotes stay in raw base units.
+    pub fn with_mint_decimals(mut self) -> Self {
+        self.mint_decimals = Some(self.mint_decimals.unwrap_or_default());
+        self
+    }
+
+    /// Also loads these token accounts on update, so swap metas with one of them as the source token account
+    /// fail early when it holds another mint than `source_mint`. Accounts that do not exist yet are skipped.
+    pub fn with_token_accounts(mut self, token_accounts: impl IntoIterator<Item = Pubkey>) -> Self {
+        self.token_account_mints.extend(token_accounts.into_iter().map(|v| (v, None)));
+        self
+    }
+
+    /// Makes `quote` fail when the price impact exceeds `max_price_impact`, a fraction like `fee_pct`.
+    /// Unset by default, quoting any impact.
+    pub fn with_max_price_impact(mut self, max_price_impact: Decimal) -> Self {
+        self.max_price_impact = Some(max_price_impact);
+        self
+    }
+
+    /// The token program of the pool mints, e.g. `spl_token_2022::id()`, `spl_token::id()` by default.
+    /// Replaced by the mint account owner on update when the mints are loaded, see `with_mint_decimals`.
+    pub fn with_token_program_id(mut self, token_program_id: Pubkey) -> Self {
+        self.token_program_id = token_program_id;
+        self
+    }
+
+    pub fn token_program_id(&self) -> Pubkey {
+        self.token_program_id
+    }
+
+    /// The decimals of `mint`, `None` until its mint account has been loaded
+    pub fn mint_decimals(&self, mint: &Pubkey) -> Option<u8> {
+        self.mint_decimals.as_ref()?.get(mint).copied()
+    }
+
+    /// `raw_amount` of `mint` in UI units, e.g. `1_500_000` of a 6 decimal mint is `1.5`
+    pub fn ui_amount(&self, mint: &Pubkey, raw_amount: u64) -> Result<Decimal> {
+        let decimals = self.mint_decimals(mint).with_context(|| format!("Decimals of mint {} are not loaded", mint))?;
+
+        Decimal::try_from_i128_with_scale(raw_amount.into(), decimals.into())
+            .with_context(|| format!("Mint {} has too many decimals ({})", mint, decimals))
+    }
+
+    pub fn quote_cache_len(&self) -> usize {
+        self.quote_cache.as_ref().map_or(0, |v| v.len())
+    }
+
+    /// The minimal set of accounts to refresh given what changed, `get_accounts_to_update` for `All`
+    pub fn get_accounts_to_update_with_context(&self, hint: AccountUpdateHint) -> Vec<Pubkey> {
+        match hint {
+            AccountUpdateHint::All => self.get_accounts_to_update(),
+            AccountUpdateHint::BalancesOnly => vec![self.key],
+        }
+    }
+
+    /// Checks that `pool_auth_pda_key` is the PDA of this pool for the stored bump, guarding against a corrupted state
+    pub fn verify_pool_auth_pda(&self) -> Result<()> {
+        let seeds: &[&[u8]] = &[POOL_AUTH_PDA_SEED, self.key.as_ref(), &[self.state.pool_auth_pda_bump]];
+        let pool_auth_pda = Pubkey::create_program_address(seeds, &self.program_id)
+            .with_context(|| format!("Invalid pool auth PDA bump {}", self.state.pool_auth_pda_bump))?;
+        if pool_auth_pda != self.state.pool_auth_pda_key {
+            return Err(anyhow!(
+                "Pool auth PDA mismatch, expected {} but the pool state has {}",
+                pool_auth_pda,
+                self.state.pool_auth_pda_key
+            ));
+        }
+        Ok(())
+    }
+
+    /// When the state was last refreshed by `update`, `None` if it is still the one loaded from the keyed account.
+    /// Only the pool state is required, see `stale_accounts` for the other accounts the update went without.
+    pub fn last_updated(&self) -> Option<SystemTime> {
+        self.last_updated
+    }
+
+    /// The accounts of `get_accounts_to_update`, other than the pool state, missing from the last successful
+    /// update. Their mint decimals, protocol fee or token account mint are the last known ones.
+    pub fn stale_accounts(&self) -> &[Pubkey] {
+        &self.stale_accounts
+    }
+
+    pub fn state(&self) -> &PoolState {
+        &self.state
+    }
+
+    pub fn lp_mint(&self) -> Pubkey {
+        self.state.pool_lp_mint_key
+    }
+
+    pub fn lp_virtual_supply(&self) -> u64 {
+        self.state.pool_lp_virtual_supply
+    }
+
+    /// Each active token's share of `lp_amount`, rounded down, in pool token array order
+    pub fn lp_to_underlying(&self, lp_amount: u64) -> Result<Vec<(Pubkey, u64)>> {
+        if self.state.pool_lp_virtual_supply == 0 {
+            return Err(anyhow!("Pool has no LP supply"));
+        }
+
+        self.state.active_token_records()
+            .map(|v| Ok((v.mint_key, proportional(v.balance, lp_amount, self.state.pool_lp_virtual_supply)?)))
+            .collect()
+    }
+
+    /// The active token records, in pool token array order
+    pub fn token_records(&self) -> impl Iterator<Item = &TokenRecord> {
+        self.state.active_token_records()
+    }
+
+    /// Each active mint with its current balance, in pool token array order
+    pub fn reserves(&self) -> Vec<(Pubkey, u64)> {
+        self.state.active_token_records().map(|v| (v.mint_key, v.balance)).collect_vec()
+    }
+
+    pub fn quoter(&self) -> PoolQuoter<'_> {
+        PoolQuoter::new(&self.state)
+            .with_rounding_policy(self.rounding_policy)
+            .with_fee_unit(self.fee_unit)
+            .with_exit_fee_rate(self.exit_fee_rate)
+            .with_protocol_fee_rate(self.protocol_fee_rate.unwrap_or_default())
+    }
+
+    /// The swap fee charged on the input, including the protocol fee when the metadata state is loaded
+    pub fn fee_rate(&self) -> FeeRate {
+        combined_fee_rate(self.pool_fee_rate, self.protocol_fee_rate.unwrap_or_default())
+    }
+
+    /// The pool TVL denominated in `reference_mint`, valuing each balance at the weighted spot price
+    pub fn total_value_in(&self, reference_mint: &Pubkey) -> Result<u64> {
+        let reference = token_record(&self.state, reference_mint)?;
+        if reference.weight == 0 {
+            return Err(anyhow!("Reference mint {} has zero weight", reference_mint));
+        }
+
+        // balance_i * spot_price_i = balance_ref * weight_i / weight_ref
+        let total_value = self.state.active_token_records()
+            .map(|v| reference.balance as u128 * v.weight as u128 / reference.weight as u128)
+            .sum::<u128>();
+
+        u64::try_from(total_value).context("total value overflow")
+    }
+
+    /// ExactIn quotes of `amount` from `mint_a` to `mint_b` and back, sharing the same fee treatment
+    pub fn quote_both_directions(&self, mint_a: &Pubkey, mint_b: &Pubkey, amount: u64) -> Result<(Quote, Quote)> {
+        let token_a = token_record(&self.state, mint_a)?;
+        let token_b = token_record(&self.state, mint_b)?;
+        let swap_fee_rate = self.fee_rate();
+
+        Ok((
+            quote_records(token_a, token_b, swap_fee_rate, self.exit_fee_rate, amount, SwapMode::ExactIn)?,
+            quote_records(token_b, token_a, swap_fee_rate, self.exit_fee_rate, amount, SwapMode::ExactIn)?,
+        ))
+    }
+
+    /// ExactIn quotes of `amount` from `input_mint` to every other active token, in pool token array order.
+    /// Each entry fails on its own. An `input_mint` not in the pool yields a single entry for it with that error.
+    pub fn quote_to_all(&self, input_mint: &Pubkey, amount: u64) -> Vec<(Pubkey, Result<Quote>)> {
+        let token_in = match token_record(&self.state, input_mint) {
+            Ok(token_in) => token_in,
+            Err(error) => return vec![(*input_mint, Err(error))],
+        };
+        let swap_fee_rate = self.fee_rate();
+
+        self.state.active_token_records()
+            .filter(|v| v.mint_key != *input_mint)
+            .map(|token_out| {
+                (token_out.mint_key, quote_records(token_in, token_out, swap_fee_rate, self.exit_fee_rate, amount, SwapMode::ExactIn))
+            })
+            .collect_vec()
+    }
+
+    /// Like `quote`, but an amount over the liquidity cap is reduced to the cap instead of only flagging
+    /// `not_enough_liquidity`, so routers can still route the largest fillable leg
+    pub fn quote_partial_fill(&self, quote_params: &QuoteParams) -> Result<Quote> {
+        let quote = self.quoter().quote(quote_params)?;
+        if !quote.not_enough_liquidity {
+            return Ok(quote);
+        }
+
+        let max_amount = match quote_params.swap_mode {
+            SwapMode::ExactIn => self.max_in_amount(&quote_params.input_mint)?,
+            SwapMode::ExactOut => self.max_out_amount(&quote_params.output_mint)?,
+        };
+
+        self.quoter().quote(&QuoteParams {
+            amount: max_amount,
+            input_mint: quote_params.input_mint,
+            output_mint: quote_params.output_mint,
+            swap_mode: quote_params.swap_mode,
+        })
+    }
+
+    /// The largest ExactIn amount of `input_mint` the pool accepts, `MAX_IN_RATIO` of its balance
+    pub fn max_in_amount(&self, input_mint: &Pubkey) -> Result<u64> {
+        Ok(token_record(&self.state, input_mint)?.typed_balance().share(MAX_IN_RATIO)?)
+    }
+
+    /// The largest ExactOut amount of `output_mint` the pool releases, `MAX_OUT_RATIO` of its balance
+    pub fn max_out_amount(&self, output_mint: &Pubkey) -> Result<u64> {
+        Ok(token_record(&self.state, output_mint)?.typed_balance().share(MAX_OUT_RATIO)?)
+    }
+
+    /// Whether an ExactIn `amount` of `input_mint` exceeds `max_in_amount`, i.e. would be quoted with
+    /// `not_enough_liquidity` and only be partially fillable, without running the quote
+    pub fn would_partial_fill(&self, input_mint: &Pubkey, amount: u64) -> Result<bool> {
+        Ok(amount > self.max_in_amount(input_mint)?)
+    }
+
+    /// The largest single-hop quote the pool supports between the two mints, at exactly `max_in_amount` for
+    /// ExactIn or `max_out_amount` for ExactOut, e.g. for depth displays
+    pub fn quote_max_in(&self, input_mint: &Pubkey, output_mint: &Pubkey, swap_mode: SwapMode) -> Result<Quote> {
+        let amount = match swap_mode {
+            SwapMode::ExactIn => self.max_in_amount(input_mint)?,
+            SwapMode::ExactOut => self.max_out_amount(output_mint)?,
+        };
+
+        self.quoter().quote(&QuoteParams {
+            amount,
+            input_mint: *input_mint,
+            output_mint: *output_mint,
+            swap_mode,
+        })
+    }
+
+    /// Like `quote`, with `fee_override` replacing the pool swap fee for this call only, e.g. to model a fee change.
+    /// The quote cache is bypassed so overridden quotes never shadow real ones.
+    pub fn quote_with_fee_override(&self, quote_params: &QuoteParams, fee_override: Option<FeeRate>) -> Result<Quote> {
+        let Some(fee_override) = fee_override else {
+            return self.quote(quote_params);
+        };
+
+        self.quoter().with_swap_fee_override(fee_override).quote(quote_params)
+    }
+
+    /// Quotes the swap and splits its fee, `referrer_fee_share` of it (rounded down) going to the referrer
+    pub fn quote_with_referrer_fee(&self, quote_params: &QuoteParams, referrer_fee_share: FeeRate) -> Result<ReferredQuote> {
+        if referrer_fee_share.0 > PONE {
+            return Err(anyhow!("Referrer fee share {} exceeds the whole fee", referrer_fee_share.0));
+        }
+        let quote = self.quote(quote_params)?;
+
+        Ok(ReferredQuote {
+            quote,
+            referrer_fee_amount: referrer_fee_share.apply(quote.fee_amount),
+        })
+    }
+
+    /// Quotes the swap and splits its fee pro rata between the pool swap fee and the protocol fee,
+    /// the protocol fee rounded down
+    pub fn quote_with_fee_components(&self, quote_params: &QuoteParams) -> Result<QuoteFeeComponents> {
+        let quote = self.quote(quote_params)?;
+        let protocol_fee_rate = self.protocol_fee_rate.unwrap_or_default();
+
+        Ok(QuoteFeeComponents {
+            quote,
+            protocol_fee_amount: proportional(quote.fee_amount, protocol_fee_rate.0, self.fee_rate().0)?,
+        })
+    }
+
+    /// Quotes the swap and returns the pool state after it, with the fee retained in the input balance
+    pub fn simulate_swap(&self, quote_params: &QuoteParams) -> Result<(Quote, PoolState)> {
+        let quote = self.quoter().quote(quote_params)?;
+
+        let mut state = self.state;
+        for record in state.pool_token_array.iter_mut() {
+            if record.mint_key == quote_params.input_mint {
+                record.balance = record.balance.checked_add(quote.in_amount).context("balance overflow")?;
+            } else if record.mint_key == quote_params.output_mint {
+                record.balance = record.balance.checked_sub(quote.out_amount).context("balance underflow")?;
+            }
+        }
+
+        Ok((quote, state))
+    }
+
+    /// `get_reserve_mints` sorted by `Pubkey` bytes, so it does not depend on the pool token array order
+    pub fn sorted_reserve_mints(&self) -> Vec<Pubkey> {
+        self.get_reserve_mints().into_iter().sorted().collect_vec()
+    }
+
+    /// Whether the pool is worth quoting, every active token holding a balance and a weight
+    pub fn is_tradeable(&self) -> bool {
+        self.state.pool_token_count >= 2
+            && self.state.active_token_records().count() >= 2
+            && self.state.active_token_records().all(|v| v.balance > 0 && v.weight > 0)
+    }
+
+    /// Whether every active token has the same weight, e.g. a classic 50/50 constant product pool
+    pub fn is_equal_weight(&self) -> bool {
+        self.state.active_token_records().map(|v| v.weight).all_equal()
+    }
+
+    /// The direction of a swap from `input_mint`, which must be one of the first two pool tokens
+    pub fn direction_of(&self, input_mint: &Pubkey) -> Result<SwapDirection> {
+        let [token_a, token_b, ..] = &self.state.pool_token_array;
+        if input_mint == &token_a.mint_key {
+            Ok(SwapDirection::AToB)
+        } else if input_mint == &token_b.mint_key {
+            Ok(SwapDirection::BToA)
+        } else {
+            Err(into_anyhow(ErrorCode::MintNotInPool)).with_context(|| format!("Mint {} is neither pool token A nor B", input_mint))
+        }
+    }
+
+    /// The mint of the active token with the largest weight, the lowest index on ties
+    pub fn dominant_token(&self) -> Pubkey {
+        self.state.active_token_records()
+            .fold(None, |dominant: Option<&TokenRecord>, v| match dominant {
+                Some(dominant) if dominant.weight >= v.weight => Some(dominant),
+                _ => Some(v),
+            })
+            .map_or(EMPTY_TOKEN_MINT, |v| v.mint_key)
+    }
+
+    /// Every directed (input, output) mint pair between active, nonzero weight tokens
+    pub fn tradeable_pairs(&self) -> Vec<(Pubkey, Pubkey)> {
+        self.get_reserve_mints()
+            .into_iter()
+            .permutations(2)
+            .map(|v| (v[0], v[1]))
+            .collect_vec()
+    }
+
+    /// `weight_in / weight_out`, the exponent of the pool pricing formula
+    pub fn weight_ratio(&self, input_mint: &Pubkey, output_mint: &Pubkey) -> Result<Decimal> {
+        let token_in = token_record(&self.state, input_mint)?;
+        let token_out = token_record(&self.state, output_mint)?;
+
+        token_in.typed_weight()
+            .ratio_to(token_out.typed_weight())
+            .context("Weight ratio undefined for a zero weight output token")
+    }
+
+    /// Output tokens per input token at the margin, excluding fees: (bO / wO) / (bI / wI)
+    pub fn spot_price(&self, input_mint: &Pubkey, output_mint: &Pubkey) -> Result<Decimal> {
+        let token_in = token_record(&self.state, input_mint)?;
+        let token_out = token_record(&self.state, output_mint)?;
+        // An empty output side quotes nothing, a zero spot price would only divide by zero further on.
+        if token_in.balance == 0 || token_out.balance == 0 || token_out.weight == 0 {
+            return Err(anyhow!("Spot price undefined for an empty or zero weight token"));
+        }
+
+        let balance_ratio = Decimal::from(token_out.balance) / Decimal::from(token_in.balance);
+        let weight_ratio = Decimal::from(token_in.weight) / Decimal::from(token_out.weight);
+
+        balance_ratio.checked_mul(weight_ratio).context("spot price overflow")
+    }
+
+    /// `spot_price / (1 - fee)`, the spot price grossed up by the swap fee charged at the margin
+    pub fn marginal_price_with_fee(&self, input_mint: &Pubkey, output_mint: &Pubkey) -> Result<Decimal> {
+        let spot_price = self.spot_price(input_mint, output_mint)?;
+
+        spot_price
+            .checked_div(Decimal::ONE - self.fee_rate().as_fraction())
+            .context("Marginal price undefined for a 100% fee")
+    }
+
+    /// The output of `amount` at the spot price, with no fee and no price impact, to compare a quote against
+    pub fn ideal_out_amount(&self, input_mint: &Pubkey, output_mint: &Pubkey, amount: u64) -> Result<Decimal> {
+        let spot_price = self.spot_price(input_mint, output_mint)?;
+
+        Decimal::from(amount).checked_mul(spot_price).context("ideal out amount overflow")
+    }
+
+    /// `1 - execution_price / spot_price`, computed in `Decimal` so it is deterministic, clamped at zero.
+    /// The execution price excludes the fee: `out_amount / (in_amount - fee_amount)`.
+    pub fn price_impact(&self, input_mint: &Pubkey, output_mint: &Pubkey, quote: &Quote) -> Result<Decimal> {
+        let spot_price = self.spot_price(input_mint, output_mint)?;
+        let adjusted_in_amount = quote.in_amount.checked_sub(quote.fee_amount).context("fee exceeds in_amount")?;
+        let execution_price = Decimal::from(quote.out_amount)
+            .checked_div(Decimal::from(adjusted_in_amount))
+            .context("price impact undefined for a zero input")?;
+
+        let price_ratio = execution_price.checked_div(spot_price).context("price impact undefined for a zero spot price")?;
+
+        Ok((Decimal::ONE - price_ratio).max(Decimal::ZERO))
+    }
+
+    /// `(ideal_out - out_amount) / ideal_out` as a percentage, the fee and the price impact of `quote` in one
+    /// figure, `ideal_out` being `ideal_out_amount` of the quote's whole `in_amount`. Clamped at zero.
+    pub fn total_cost_pct(&self, input_mint: &Pubkey, output_mint: &Pubkey, quote: &Quote) -> Result<Decimal> {
+        let ideal_out_amount = self.ideal_out_amount(input_mint, output_mint, quote.in_amount)?;
+        let cost = (ideal_out_amount - Decimal::from(quote.out_amount))
+            .checked_div(ideal_out_amount)
+            .context("total cost undefined for a zero ideal output")?;
+
+        Ok((cost * Decimal::ONE_HUNDRED).max(Decimal::ZERO))
+    }
+
+    /// The input amount whose swap moves the spot price down to `target_price`, clamped to `MAX_IN_RATIO`.
+    /// Zero if the spot price is already at or below the target.
+    pub fn amount_to_target_price(&self, input_mint: &Pubkey, output_mint: &Pubkey, target_price: Decimal) -> Result<u64> {
+        if target_price <= Decimal::ZERO {
+            return Err(anyhow!("Target price must be positive"));
+        }
+        let spot_price = self.spot_price(input_mint, output_mint)?;
+        if target_price >= spot_price {
+            return Ok(0);
+        }
+
+        let token_in = token_record(&self.state, input_mint)?;
+        let token_out = token_record(&self.state, output_mint)?;
+
+        // spot' = spot * (bI / bI') ^ ((wI + wO) / wO), solved for the post-fee input bI' - bI. The deterministic
+        // `pow_minus_one` of the quote math keeps the answer identical across platforms.
+        let price_ratio = (spot_price / target_price).to_f64().context("price ratio overflow")?;
+        let exponent = token_out.weight as f64 / (token_in.weight as f64 + token_out.weight as f64);
+        let adjusted_in = token_in.balance as f64 * pow_minus_one(price_ratio - 1.0, exponent);
+        let token_in_amount = adjusted_in / (1.0 - self.fee_rate().0 as f64 / PONE as f64);
+
+        let max_token_in_amount = value_from_shares(MAX_IN_RATIO, token_in.balance, PONE)?;
+
+        Ok((token_in_amount.ceil() as u64).min(max_token_in_amount))
+    }
+
+    /// The largest input whose quote keeps `price_impact` at or under `max_impact_pct`. Despite the name it is a
+    /// fraction like `price_impact` itself, `0.001` meaning 0.1%, not a percentage.
+    /// Clamped to `MAX_IN_RATIO`, found by bisection since the weighted pool impact has no closed form inverse.
+    pub fn max_in_for_price_impact(&self, input_mint: &Pubkey, output_mint: &Pubkey, max_impact_pct: Decimal) -> Result<u64> {
+        if max_impact_pct < Decimal::ZERO || max_impact_pct >= Decimal::ONE {
+            return Err(anyhow!("Price impact threshold must be in [0, 1)"));
+        }
+        let token_in = token_record(&self.state, input_mint)?;
+        let token_out = token_record(&self.state, output_mint)?;
+        let swap_fee_rate = self.fee_rate();
+
+        // Dust inputs without an output have no price impact to measure, they never bound the search.
+        // Any other quote failure is a real one and is returned.
+        let within_impact = |amount: u64| -> Result<bool> {
+            match quote_records(token_in, token_out, swap_fee_rate, self.exit_fee_rate, amount, SwapMode::ExactIn) {
+                Ok(quote) => Ok(self.price_impact(input_mint, output_mint, &quote)? <= max_impact_pct),
+                Err(error) => match convert_anchor_error(&error) {
+                    Some(ErrorCode::ValidationTooSmallTokenInAmount | ErrorCode::ValidationTooSmallTokenOutAmount) => Ok(true),
+                    _ => Err(error),
+                },
+            }
+        };
+
+        let max_token_in_amount = value_from_shares(MAX_IN_RATIO, token_in.balance, PONE)?;
+        if within_impact(max_token_in_amount)? {
+            return Ok(max_token_in_amount);
+        }
+
+        let (mut low, mut high) = (0, max_token_in_amount);
+        while high - low > 1 {
+            let mid = low + (high - low) / 2;
+            if within_impact(mid)? {
+                low = mid;
+            } else {
+                high = mid;
+            }
+        }
+
+        Ok(low)
+    }
+
+    /// The ExactOut input buying `fraction` of the `output_mint` balance, e.g. `0.1` for 10% of it.
+    /// The output is rounded down, a fraction above `MAX_OUT_RATIO` is rejected rather than clamped.
+    pub fn in_for_out_fraction(&self, input_mint: &Pubkey, output_mint: &Pubkey, fraction: Decimal) -> Result<u64> {
+        if fraction <= Decimal::ZERO {
+            return Err(anyhow!("Output fraction must be positive"));
+        }
+        let token_out = token_record(&self.state, output_mint)?;
+
+        let token_out_amount = (Decimal::from(token_out.balance) * fraction)
+            .floor()
+            .to_u64()
+            .context("output amount overflow")?;
+        if token_out_amount > value_from_shares(MAX_OUT_RATIO, token_out.balance, PONE)? {
+            return Err(into_anyhow(ErrorCode::ValidationLiquidityTooBigTokenOutAmount))
+                .with_context(|| format!("Output fraction {} exceeds the liquidity cap", fraction));
+        }
+
+        let quote = self.quoter().quote(&QuoteParams {
+            amount: token_out_amount,
+            input_mint: *input_mint,
+            output_mint: *output_mint,
+            swap_mode: SwapMode::ExactOut,
+        })?;
+
+        Ok(quote.in_amount)
+    }
+
+    /// The smallest ExactIn input whose effective price `out_amount / in_amount` is within the fees of the spot
+    /// price, at least `spot * (1 - fee) * (1 - exit_fee)`. Smaller trades lose more to rounding than that.
+    /// Errors if no input qualifies among the first `BREAK_EVEN_SCAN_LIMIT` output amounts.
+    pub fn break_even_in(&self, input_mint: &Pubkey, output_mint: &Pubkey) -> Result<u64> {
+        let swap_fee_rate = self.fee_rate();
+        if swap_fee_rate.0 == 0 {
+            return Err(anyhow!("Break-even is undefined for a pool without a fee"));
+        }
+        let token_in = token_record(&self.state, input_mint)?;
+        let token_out = token_record(&self.state, output_mint)?;
+        let target_price = self.spot_price(input_mint, output_mint)?
+            * (Decimal::ONE - swap_fee_rate.as_fraction())
+            * (Decimal::ONE - self.exit_fee_rate.as_fraction());
+
+        // The output of `amount` once the fee is taken off, so the fee itself is charged exactly as in a quote.
+        // Dust has no output, any other failing quote is returned.
+        let adjusted_in_amount = |amount: u64| amount - swap_fee_rate.apply(amount);
+        let out_amount = |adjusted_in_amount: u64| -> Result<u64> {
+            match quote_records(token_in, token_out, FeeRate::default(), self.exit_fee_rate, adjusted_in_amount, SwapMode::ExactIn) {
+                Ok(quote) => Ok(quote.out_amount),
+                Err(error) => match convert_anchor_error(&error) {
+                    Some(ErrorCode::ValidationTooSmallTokenInAmount | ErrorCode::ValidationTooSmallTokenOutAmount) => Ok(0),
+                    _ => Err(error),
+                },
+            }
+        };
+        let reaches = |amount: u64, out_amount: u64| Decimal::from(out_amount) >= Decimal::from(amount) * target_price;
+
+        // Rounding is worth less than one more unit of adjusted input and one more unit of output. Once even those
+        // miss the target the price impact alone costs more than the fees, and so it does for every larger input.
+        let can_reach = |amount: u64| -> Result<bool> { Ok(reaches(amount, out_amount(adjusted_in_amount(amount) + 1)? + 1)) };
+        let (mut low, mut high) = (0, self.max_in_amount(input_mint)?);
+        while low < high {
+            let mid = low + (high - low).div_ceil(2);
+            if can_reach(mid)? {
+                low = mid;
+            } else {
+                high = mid - 1;
+            }
+        }
+        let max_amount = low;
+
+        // The output never decreases with the input, so it steps through levels. Within a level the effective
+        // price falls as the input grows, only the smallest input of each level can be the first to break even.
+        // The next level starts are found by galloping then bisecting, so each level costs a few quotes.
+        let next_level = |amount: u64, level: u64| -> Result<Option<u64>> {
+            let (mut below, mut step) = (amount, 1);
+            let mut above = loop {
+                let candidate = amount.saturating_add(step).min(max_amount);
+                if out_amount(adjusted_in_amount(candidate))? > level {
+                    break candidate;
+                }
+                if candidate == max_amount {
+                    return Ok(None);
+                }
+                (below, step) = (candidate, step.saturating_mul(2));
+            };
+            while above - below > 1 {
+                let mid = below + (above - below) / 2;
+                if out_amount(adjusted_in_amount(mid))? > level {
+                    above = mid;
+                } else {
+                    below = mid;
+                }
+            }
+            Ok(Some(above))
+        };
+
+        let mut amount = 0;
+        for _ in 0..BREAK_EVEN_SCAN_LIMIT {
+            let Some(level_start) = next_level(amount, out_amount(adjusted_in_amount(amount))?)? else {
+                break;
+            };
+            if reaches(level_start, out_amount(adjusted_in_amount(level_start))?) {
+                return Ok(level_start);
+            }
+            amount = level_start;
+        }
+
+        Err(anyhow!("No break-even trade size found"))
+    }
+
+    /// Re-expresses the input-denominated `quote.fee_amount` in output tokens at the spot price, for display
+    pub fn fee_in_output_token(&self, quote: &Quote, output_mint: &Pubkey) -> Result<Decimal> {
+        let spot_price = self.spot_price(&quote.fee_mint, output_mint)?;
+
+        Decimal::from(quote.fee_amount).checked_mul(spot_price).context("fee overflow")
+    }
+
+    /// A human readable, multi-line summary of a quote alongside the pool context, for logging
+    pub fn quote_summary(&self, quote_params: &QuoteParams, quote: &Quote) -> String {
+        let mut summary = format!(
+            "{} pool {}\n\
+             swap {:?} {} -> {}\n\
+             in_amount: {}, out_amount: {}, not_enough_liquidity: {}\n\
+             fee: {} of {} ({}%)\n",
+            self.label(),
+            self.key,
+            quote_params.swap_mode,
+            quote_params.input_mint,
+            quote_params.output_mint,
+            quote.in_amount,
+            quote.out_amount,
+            quote.not_enough_liquidity,
+            quote.fee_amount,
+            quote.fee_mint,
+            (quote.fee_pct * Decimal::ONE_HUNDRED).normalize(),
+        );
+        for record in self.state.active_token_records() {
+            summary.push_str(&format!(
+                "token {}: balance {}, weight {}/{}\n",
+                record.mint_key, record.balance, record.weight, self.state.pool_token_total_weight,
+            ));
+        }
+        summary
+    }
+
+    /// A label distinguishing pools by token count and fee tier, e.g. `1DEX-2tok-0.30%`
+    pub fn pool_descriptor(&self) -> String {
+        format!(
+            "{}-{}tok-{:.2}%",
+            self.label(),
+            self.state.pool_token_count,
+            self.fee_rate().as_fraction() * Decimal::ONE_HUNDRED,
+        )
+    }
+
+    /// An estimated compute unit limit for a swap against this pool, for the `ComputeBudget` instruction.
+    /// `token_2022` accounts for both transfers (user in, pool out) going through Token-2022.
+    pub fn estimate_swap_compute_units(&self, token_2022: bool) -> u32 {
+        // The count is validated on parse, clamping it keeps the product in range for any state.
+        let token_units = SWAP_COMPUTE_UNITS_PER_POOL_TOKEN * self.state.pool_token_count.min(MAX_TOKEN_COUNT as u64) as u32;
+        let transfer_units = if token_2022 { 2 * TOKEN_2022_TRANSFER_COMPUTE_UNITS } else { 0 };
+
+        SWAP_BASE_COMPUTE_UNITS + token_units + transfer_units
+    }
+
+    /// Every account a swap touches, deduplicated: the program-derived accounts (pool auth PDA, metadata state,
+    /// token auth PDA), the pool and user accounts, and the token program. A superset of the swap account metas,
+    /// the token auth PDA being only implied by the metadata swap fee account. Pool token accounts are left out
+    /// for mints not in the pool.
+    pub fn swap_related_accounts(&self, swap_params: &SwapParams) -> Vec<Pubkey> {
+        let pool_token_accounts = [swap_params.source_mint, swap_params.destination_mint]
+            .into_iter()
+            .filter_map(|mint| self.state.token_record(&mint).map(|v| v.account_key));
+
+        [
+            self.state.pool_auth_pda_key,
+            ONE_INTRO_METADATA_STATE,
+            ONE_INTRO_TOKEN_AUTH_PDA,
+            self.key,
+            get_associated_token_address_with_program_id(&ONE_INTRO_TOKEN_AUTH_PDA, &swap_params.source_mint, &self.token_program_id),
+        ]
+        .into_iter()
+        .chain(pool_token_accounts)
+        .chain([
+            swap_params.token_transfer_authority,
+            swap_params.source_token_account,
+            swap_params.destination_token_account,
+            self.token_program_id,
+        ])
+        .unique()
+        .collect_vec()
+    }
+
+    /// The wSOL wrap and unwrap steps a swap needs, in transaction order, empty if neither side is wSOL
+    pub fn wrapped_sol_steps(&self, swap_params: &SwapParams) -> Vec<WrappedSolStep> {
+        if swap_params.source_mint == spl_token::native_mint::ID {
+            vec![
+                WrappedSolStep::CreateTemporaryAccount,
+                WrappedSolStep::Wrap { lamports: swap_params.in_amount },
+                WrappedSolStep::CloseTemporaryAccount,
+            ]
+        } else if swap_params.destination_mint == spl_token::native_mint::ID {
+            vec![WrappedSolStep::CreateTemporaryAccount, WrappedSolStep::CloseTemporaryAccount]
+        } else {
+            vec![]
+        }
+    }
+
+    /// Like `get_swap_and_account_metas`, with `authority` deciding whether the `user` meta is a signer
+    pub fn get_swap_and_account_metas_with_authority(
+        &self,
+        swap_params: &SwapParams,
+        authority: TransferAuthority,
+    ) -> Result<SwapAndAccountMetas> {
+        let result = self.swap_and_account_metas(swap_params, authority);
+
+        #[cfg(feature = "tracing")]
+        if let Err(error) = &result {
+            tracing::warn!(
+                pool = %self.key,
+                source_mint = %swap_params.source_mint,
+                destination_mint = %swap_params.destination_mint,
+                error = %error,
+                "1DEX account metas failed"
+            );
+        }
+
+        result
+    }
+
+    /// `swap_instruction` with the minimum out derived from the quoted `swap_params.out_amount`, so the
+    /// program rejects the swap if the output drops more than `slippage_bps` below the quote
+    pub fn swap_instruction_with_slippage(&self, swap_params: &SwapParams, slippage_bps: u16) -> Result<Instruction> {
+        self.swap_instruction(swap_params, minimum_out_amount(swap_params.out_amount, slippage_bps)?)
+    }
+
+    pub fn swap_instruction(&self, swap_params: &SwapParams, minimum_token_out_amount: u64) -> Result<Instruction> {
+        let SwapAndAccountMetas { account_metas, .. } = self.get_swap_and_account_metas(swap_params)?;
+
+        Ok(Instruction {
+            program_id: self.program_id,
+            accounts: account_metas,
+            data: SwapInstructionArgs {
+                token_in_amount: swap_params.in_amount,
+                minimum_token_out_amount,
+            }
+            .data(),
+        })
+    }
+}
+
+impl TryFrom<&KeyedAccount> for OneIntroAmm {
+    type Error = anyhow::Error;
+
+    fn try_from(keyed_account: &KeyedAccount) -> Result<Self> {
+        OneIntroAmm::from_keyed_account(keyed_account)
+    }
+}
+
 impl Amm for OneIntroAmm {
     fn key(&self) -> Pubkey {
         self.key
@@ -47,92 +1190,235 @@ impl Amm for OneIntroAmm {
     }
 
     fn from_keyed_account(keyed_account: &KeyedAccount) -> Result<Self> {
-        Ok(OneIntroAmm {
-            key: keyed_account.key,
-            program_id: keyed_account.account.owner,
-            state: PoolState::deserialize(&mut &keyed_account.account.data[8..])?, // Skip the first 8-byte Anchor discriminator.
-        })
+        let state = parse_pool_state(&keyed_account.account.data)?;
+
+        Ok(OneIntroAmm::new(keyed_account.key, keyed_account.account.owner, state))
     }
 
+    // Mints follow the order of `pool_token_array`, use `sorted_reserve_mints` for a stable order.
     fn get_reserve_mints(&self) -> Vec<Pubkey> {
-        self.state.pool_token_array
-            .map(|v| v.mint_key)
-            .into_iter()
-            .filter(|v| !v.eq(&pubkey!("11111111111111111111111111111111")))
-            .collect_vec()
+        self.reserve_mints.clone()
     }
 
+    // Token balances are tracked in the pool state, the pool token accounts are never required.
     fn get_accounts_to_update(&self) -> Vec<Pubkey> {
-        vec![self.key]
+        let mut accounts = vec![self.key];
+        if self.protocol_fee_rate.is_some() {
+            accounts.push(ONE_INTRO_METADATA_STATE);
+        }
+        if self.mint_decimals.is_some() {
+            accounts.extend(self.reserve_mints.iter().copied());
+        }
+        accounts.extend(self.token_account_mints.keys().copied());
+        accounts
     }
 
-    fn update(&mut self, _account_map: &AccountMap) -> Result<()> {
-        let account = _account_map.get(&self.key).context("Pool state not found.")?;
+    fn update(&mut self, account_map: &AccountMap) -> Result<()> {
+        let result = self.update_state(account_map);
 
-        self.state = PoolState::deserialize(&mut &account.data[8..])?;
+        #[cfg(feature = "tracing")]
+        match &result {
+            Ok(()) if !self.stale_accounts.is_empty() => tracing::warn!(
+                pool = %self.key,
+                stale_accounts = ?self.stale_accounts,
+                "1DEX pool updated without some accounts, keeping their last known values"
+            ),
+            Ok(()) => tracing::debug!(pool = %self.key, "1DEX pool updated"),
+            Err(error) => tracing::warn!(pool = %self.key, error = %error, "1DEX pool update failed"),
+        }
 
-        Ok(())
+        result
     }
 
+    // Amounts over the liquidity cap are `Ok` with `not_enough_liquidity` set for the router to discard or cap
+    // them, an ExactOut of the whole output balance or more being quoted at the cap. `Err` is reserved for
+    // invalid params and math failures, e.g. dust or an amount that does not fit a u64.
     fn quote(&self, quote_params: &QuoteParams) -> Result<Quote> {
-        let record_0 = &self.state.pool_token_array[0];
-        let record_1 = &self.state.pool_token_array[1];
-        let swap_fee_ratio = self.state.pool_swap_fee_ratio;
+        let result = self.checked_quote(quote_params);
 
-        let (token_in_balance, token_out_balance, token_in_weight, token_out_weight) =
-            if quote_params.input_mint == self.state.pool_token_array[0].mint_key {
-                (record_0.balance, record_1.balance, record_0.weight, record_1.weight)
-            } else {
-                (record_1.balance, record_0.balance, record_1.weight, record_0.weight)
-            };
+        #[cfg(feature = "tracing")]
+        match &result {
+            Ok(quote) => tracing::debug!(
+                pool = %self.key,
+                input_mint = %quote_params.input_mint,
+                output_mint = %quote_params.output_mint,
+                amount = quote_params.amount,
+                in_amount = quote.in_amount,
+                out_amount = quote.out_amount,
+                not_enough_liquidity = quote.not_enough_liquidity,
+                "1DEX quote"
+            ),
+            Err(error) => tracing::warn!(
+                pool = %self.key,
+                input_mint = %quote_params.input_mint,
+                output_mint = %quote_params.output_mint,
+                amount = quote_params.amount,
+                error = %error,
+                "1DEX quote failed"
+            ),
+        }
 
-        let (in_amount, out_amount, fee_amount, not_enough_liquidity) = match quote_params.swap_mode {
-            SwapMode::ExactIn => {
-                swap_exact_amount_in(
-                    token_in_balance,
-                    token_in_weight,
-                    token_out_balance,
-                    token_out_weight,
-                    quote_params.amount,
-                    swap_fee_ratio
-                )?
-            },
-            SwapMode::ExactOut => {
-                swap_exact_amount_out(
-                    token_in_balance,
-                    token_in_weight,
-                    token_out_balance,
-                    token_out_weight,
-                    quote_params.amount,
-                    swap_fee_ratio
-                )?
-            },
-        };
+        result
+    }
 
-        Ok(Quote {
-            in_amount,
-            out_amount,
-            fee_amount,
-            fee_mint: quote_params.input_mint,
-            fee_pct: Decimal::new((swap_fee_ratio * 100) as i64, 9),
-            not_enough_liquidity,
-            ..Quote::default()
-        })
+    // Both swap modes are quoted, see `quote_records`.
+    fn supports_exact_out(&self) -> bool {
+        true
     }
 
     fn get_swap_and_account_metas(&self, swap_params: &SwapParams) -> Result<SwapAndAccountMetas> {
-        let record_0 = self.state.pool_token_array[0];
-        let record_1 = self.state.pool_token_array[1];
+        self.get_swap_and_account_metas_with_authority(swap_params, TransferAuthority::Signer)
+    }
+}
 
-        let (pool_token_in_account, pool_token_out_account) =
-            if swap_params.source_mint == record_0.mint_key {
-                (record_0.account_key, record_1.account_key)
-            } else {
-                (record_1.account_key, record_0.account_key)
-            };
+impl OneIntroAmm {
+    fn new(key: Pubkey, program_id: Pubkey, state: PoolState) -> Self {
+        OneIntroAmm {
+            key,
+            program_id,
+            state,
+            quote_cache: None,
+            rounding_policy: RoundingPolicy::default(),
+            fee_unit: FeeUnit::default(),
+            // `validate_pool_state` already bounds the ratio in the default `FeeUnit::Pone`.
+            pool_fee_rate: FeeRate(state.pool_swap_fee_ratio),
+            exit_fee_rate: FeeRate::default(),
+            protocol_fee_rate: None,
+            mint_decimals: None,
+            max_price_impact: None,
+            token_account_mints: HashMap::new(),
+            token_program_id: spl_token::id(),
+            reserve_mints: reserve_mints(&state),
+            last_updated: None,
+            stale_accounts: Vec::new(),
+        }
+    }
+
+    fn update_state(&mut self, account_map: &AccountMap) -> Result<()> {
+        let account = account_map.get(&self.key).context("Pool state not found.")?;
+
+        // Only the pool state is required, the other accounts keep their last known values while absent.
+        let state = parse_pool_state(&account.data)?;
+        let protocol_fee_rate = match (self.protocol_fee_rate, account_map.get(&ONE_INTRO_METADATA_STATE)) {
+            (Some(_), Some(account)) => Some(FeeRate(parse_metadata_state(&account.data)?.protocol_swap_fee_ratio)),
+            (protocol_fee_rate, _) => protocol_fee_rate,
+        };
+        let pool_fee_rate = self.fee_unit.fee_rate(state.pool_swap_fee_ratio)?;
+        let protocol_fee_rate = protocol_fee_rate
+            .map(|v| validate_protocol_fee_rate(pool_fee_rate, v))
+            .transpose()?;
+
+        self.last_updated = Some(SystemTime::now());
+        // Mint decimals and the token program do not enter quotes, an otherwise identical refresh keeps the cache.
+        if state != self.state || protocol_fee_rate != self.protocol_fee_rate {
+            self.state = state;
+            self.pool_fee_rate = pool_fee_rate;
+            self.protocol_fee_rate = protocol_fee_rate;
+            self.reserve_mints = reserve_mints(&self.state);
+            if let Some(quote_cache) = &self.quote_cache {
+                quote_cache.clear();
+            }
+        }
+
+        // The new state is already applied, so a bad mint or token account only fails their own refresh.
+        // After a mint change the new mints are only requested from the next update on.
+        if let Some(mint_decimals) = &mut self.mint_decimals {
+            let mint_accounts = self.reserve_mints.iter()
+                .filter_map(|mint| Some((*mint, account_map.get(mint)?)))
+                .collect_vec();
+            for (mint, account) in &mint_accounts {
+                mint_decimals.insert(*mint, parse_mint_decimals(&account.data).with_context(|| format!("Invalid mint {}", mint))?);
+            }
+
+            // The swap instruction takes a single token program, so every mint must share it.
+            match mint_accounts.iter().map(|(_, account)| account.owner).all_equal_value() {
+                Ok(token_program_id) if token_program_id != spl_token::id() && token_program_id != spl_token_2022::id() => {
+                    return Err(anyhow!("Pool mints are owned by {}, not a token program", token_program_id));
+                },
+                Ok(token_program_id) => self.token_program_id = token_program_id,
+                Err(Some(_)) => return Err(anyhow!("Pool mints are owned by different token programs")),
+                Err(None) => {},
+            }
+        }
+
+        for (token_account, mint) in self.token_account_mints.iter_mut() {
+            if let Some(account) = account_map.get(token_account) {
+                *mint = Some(parse_token_account_mint(&account.data).with_context(|| format!("Invalid token account {}", token_account))?);
+            }
+        }
+
+        // Requested from the updated state, so mints new to it count as stale until they are loaded.
+        self.stale_accounts = self.get_accounts_to_update()
+            .into_iter()
+            .filter(|v| *v != self.key && !account_map.contains_key(v))
+            .collect_vec();
+
+        Ok(())
+    }
+
+    fn checked_quote(&self, quote_params: &QuoteParams) -> Result<Quote> {
+        let quote = self.cached_quote(quote_params)?;
+
+        // A quote already flagged `not_enough_liquidity` is left for the router to discard or cap.
+        if let Some(max_price_impact) = self.max_price_impact.filter(|_| !quote.not_enough_liquidity) {
+            let price_impact = self.price_impact(&quote_params.input_mint, &quote_params.output_mint, &quote)?;
+            if price_impact > max_price_impact {
+                return Err(anyhow!("Price impact {} exceeds the maximum of {}", price_impact, max_price_impact));
+            }
+        }
+
+        Ok(quote)
+    }
+
+    fn cached_quote(&self, quote_params: &QuoteParams) -> Result<Quote> {
+        let Some(quote_cache) = &self.quote_cache else {
+            return self.quoter().quote(quote_params);
+        };
+
+        let key = QuoteCache::key(quote_params);
+        if let Some(quote) = quote_cache.get(&key) {
+            return Ok(quote);
+        }
+        let quote = self.quoter().quote(quote_params)?;
+        quote_cache.insert(key, quote);
+
+        Ok(quote)
+    }
+
+    fn swap_and_account_metas(&self, swap_params: &SwapParams, authority: TransferAuthority) -> Result<SwapAndAccountMetas> {
+        for (name, key) in [
+            ("source_token_account", swap_params.source_token_account),
+            ("destination_token_account", swap_params.destination_token_account),
+            ("token_transfer_authority", swap_params.token_transfer_authority),
+        ] {
+            if key == Pubkey::default() {
+                return Err(anyhow!("Swap params {} is the default pubkey", name));
+            }
+        }
+
+        if let Some(Some(mint)) = self.token_account_mints.get(&swap_params.source_token_account) {
+            if *mint != swap_params.source_mint {
+                return Err(anyhow!(
+                    "Source token account {} holds mint {}, not the source mint {}",
+                    swap_params.source_token_account,
+                    mint,
+                    swap_params.source_mint
+                ));
+            }
+        }
+
+        let pool_token_in_account = token_record(&self.state, &swap_params.source_mint)?.account_key;
+        let pool_token_out = token_record(&self.state, &swap_params.destination_mint)?;
+        // An empty output side cannot fill any swap, building the transaction would only waste its fees.
+        if pool_token_out.balance == 0 {
+            return Err(into_anyhow(ErrorCode::NotEnoughLiquidity))
+                .with_context(|| format!("Pool has no {} balance to swap out", swap_params.destination_mint));
+        }
+        let pool_token_out_account = pool_token_out.account_key;
 
         let user = swap_params.token_transfer_authority;
-        let ata_metadata_swap_fee = get_associated_token_address(&ONE_INTRO_TOKEN_AUTH_PDA, &swap_params.source_mint);
+        let ata_metadata_swap_fee =
+            get_associated_token_address_with_program_id(&ONE_INTRO_TOKEN_AUTH_PDA, &swap_params.source_mint, &self.token_program_id);
 
         Ok(SwapAndAccountMetas {
             swap: Swap::TokenSwap, // TODO How to add 1INTRO to Swap enum?
@@ -142,67 +1428,391 @@ impl Amm for OneIntroAmm {
                 AccountMeta::new_readonly(self.state.pool_auth_pda_key, false), // poolAuthPda
                 AccountMeta::new(pool_token_in_account, false), // poolTokenInAccount
                 AccountMeta::new(pool_token_out_account, false), // poolTokenOutAccount
-                AccountMeta::new(user, true), // user
+                AccountMeta::new(user, authority == TransferAuthority::Signer), // user
                 AccountMeta::new(swap_params.source_token_account, false), // userTokenInAccount
                 AccountMeta::new(swap_params.destination_token_account, false), // userTokenOutAccount
                 AccountMeta::new(ata_metadata_swap_fee, false), // metadataSwapFeeAccount
                 AccountMeta::new(self.key, false), // referrerTokenAccount
-                AccountMeta::new_readonly(spl_token::id(), false), // tokenProgram
+                AccountMeta::new_readonly(self.token_program_id, false), // tokenProgram
             ]),
         })
     }
 }
 
+/// ln of the weighted geometric mean invariant `prod(b_i ^ (w_i / W))`, summed in log space to avoid overflow.
+/// A swap should never decrease it, the fee retained by the pool increases it.
+pub fn invariant_ln(state: &PoolState) -> f64 {
+    let total_weight = state.active_token_records().map(|v| v.weight as f64).sum::<f64>();
+
+    state.active_token_records()
+        .filter(|v| v.weight > 0)
+        .map(|v| v.weight as f64 / total_weight * (v.balance as f64).ln())
+        .sum()
+}
+
+/// `out_amount` reduced by `slippage_bps`, rounded down
+pub fn minimum_out_amount(out_amount: u64, slippage_bps: u16) -> Result<u64> {
+    if slippage_bps > 10_000 {
+        return Err(anyhow!("Slippage of {} bps exceeds 100%", slippage_bps));
+    }
+    Ok(proportional(out_amount, 10_000 - slippage_bps as u64, 10_000)?)
+}
+
+fn reserve_mints(state: &PoolState) -> Vec<Pubkey> {
+    state.active_token_records()
+        .filter(|v| v.weight > 0)
+        .map(|v| v.mint_key)
+        .collect_vec()
+}
+
+fn parse_pool_state(data: &[u8]) -> Result<PoolState> {
+    // Skip the first 8-byte Anchor discriminator.
+    let mut data = data.get(8..).context("Pool state account is shorter than its discriminator")?;
+    validate_pool_state(PoolState::deserialize(&mut data)?)
+}
+
+fn validate_pool_state(mut state: PoolState) -> Result<PoolState> {
+    ensure_tradeable_token_count(&state)?;
+    // A fee of 100% or more leaves nothing to swap, ExactIn would otherwise quote it silently.
+    // Checked in the default `FeeUnit::Pone` here, `with_fee_unit` and updates check it again once converted.
+    FeeUnit::Pone.fee_rate(state.pool_swap_fee_ratio)?;
+    if let Some(mint) = state.active_token_records().map(|v| v.mint_key).duplicates().next() {
+        return Err(anyhow!("Mint {} is listed more than once in the pool", mint));
+    }
+
+    // The stored total must match the active weights, recompute it rather than trusting the account.
+    let total_weight = state.active_token_records()
+        .try_fold(0u64, |total, v| total.checked_add(v.weight))
+        .context("pool_token_total_weight overflow")?;
+    state.pool_token_total_weight = total_weight;
+
+    Ok(state)
+}
+
+fn parse_metadata_state(data: &[u8]) -> Result<MetadataState> {
+    // Another account at the metadata state key must not be read as a fee.
+    let mut data = data.strip_prefix(&METADATA_STATE_DISCRIMINATOR).context("Account is not a 1DEX metadata state")?;
+    Ok(MetadataState::deserialize(&mut data)?)
+}
+
+// Like the pool fee alone in `validate_pool_state`, the fee charged with the protocol fee on top must stay below 100%.
+fn validate_protocol_fee_rate(pool_fee_rate: FeeRate, protocol_fee_rate: FeeRate) -> Result<FeeRate> {
+    match pool_fee_rate.0.checked_add(protocol_fee_rate.0) {
+        Some(fee_rate) if fee_rate < PONE => Ok(protocol_fee_rate),
+        _ => Err(anyhow!(
+            "Swap fee ratio {} plus protocol fee ratio {} is not below PONE",
+            pool_fee_rate.0,
+            protocol_fee_rate.0
+        )),
+    }
+}
+
+// Token-2022 mints share the legacy `Mint` layout, followed by their extensions, so both parse the same way.
+fn parse_mint_decimals(data: &[u8]) -> Result<u8> {
+    Ok(StateWithExtensions::<Mint>::unpack(data)?.base.decimals)
+}
+
+fn parse_token_account_mint(data: &[u8]) -> Result<Pubkey> {
+    Ok(StateWithExtensions::<spl_token_2022::state::Account>::unpack(data)?.base.mint)
+}
+
+// The protocol fee is charged on the input together with the pool fee. `validate_protocol_fee_rate` keeps loaded
+// fees below PONE, the saturation only guards configured ones.
+fn combined_fee_rate(pool_fee_rate: FeeRate, protocol_fee_rate: FeeRate) -> FeeRate {
+    FeeRate(pool_fee_rate.0.saturating_add(protocol_fee_rate.0))
+}
+
+fn ensure_tradeable_token_count(state: &PoolState) -> Result<()> {
+    if state.pool_token_count < 2 {
+        return Err(anyhow!("Pool has {} token(s), at least 2 are needed to swap", state.pool_token_count));
+    }
+    Ok(())
+}
+
+fn into_anyhow(error_code: ErrorCode) -> anyhow::Error {
+    anchor_lang::error::Error::from(error_code).into()
+}
+
+/// Recovers the `ErrorCode` an error originated from, looking through any added context
+pub fn convert_anchor_error(error: &anyhow::Error) -> Option<ErrorCode> {
+    error.chain().find_map(|cause| match cause.downcast_ref::<anchor_lang::error::Error>()? {
+        anchor_lang::error::Error::AnchorError(anchor_error) => {
+            ErrorCode::from_error_code_number(anchor_error.error_code_number)
+        },
+        anchor_lang::error::Error::ProgramError(_) => None,
+    })
+}
+
+fn token_record<'a>(state: &'a PoolState, mint: &Pubkey) -> Result<&'a TokenRecord> {
+    state.token_record(mint)
+        .ok_or_else(|| into_anyhow(ErrorCode::MintNotInPool))
+        .with_context(|| format!("Mint {} not in pool", mint))
+}
+
+/// Quotes a swap against a pool state, without needing an `OneIntroAmm`.
+/// The fee is taken on the input for both swap modes, so `fee_mint` is always the input mint.
+pub fn quote_from_state(
+    state: &PoolState,
+    input_mint: Pubkey,
+    output_mint: Pubkey,
+    amount: u64,
+    swap_mode: SwapMode,
+) -> Result<Quote> {
+    quote_state(state, input_mint, output_mint, amount, swap_mode, FeeRate(state.pool_swap_fee_ratio), FeeRate::default())
+}
+
+/// Quotes a swap from balances and weights alone, e.g. read from the token accounts and another source,
+/// instead of a `PoolState`. `fee_ratio` is relative to `PONE`, `amount` is interpreted per `swap_mode`.
+pub fn quote_raw(
+    balance_in: u64,
+    weight_in: u64,
+    balance_out: u64,
+    weight_out: u64,
+    fee_ratio: u64,
+    amount: u64,
+    swap_mode: SwapMode,
+) -> Result<RawQuote> {
+    if weight_in == 0 || weight_out == 0 {
+        return Err(anyhow!("Cannot quote a swap involving a zero weight token"));
+    }
+
+    let swap = match swap_mode {
+        SwapMode::ExactIn => swap_exact_amount_in,
+        SwapMode::ExactOut => swap_exact_amount_out,
+    };
+    let (in_amount, out_amount, fee_amount, not_enough_liquidity) =
+        swap(Balance(balance_in), Weight(weight_in), Balance(balance_out), Weight(weight_out), amount, FeeRate(fee_ratio), FeeRate::default())?;
+
+    Ok(RawQuote {
+        in_amount,
+        out_amount,
+        fee_amount,
+        not_enough_liquidity,
+    })
+}
+
+/// The sum of the quotes' `fee_amount`s, which must share a `fee_mint`
+pub fn total_fee_amount(quotes: &[Quote]) -> Result<u64> {
+    sum_fee_amounts(quotes.iter().map(|v| v.fee_amount))
+}
+
+// Accumulated in u128 so no realistic number of fees can wrap, the total must still fit a u64.
+fn sum_fee_amounts(fee_amounts: impl IntoIterator<Item = u64>) -> Result<u64> {
+    let total = fee_amounts.into_iter().map(u128::from).sum::<u128>();
+
+    u64::try_from(total).map_err(|_| anyhow!("Total fee amount {} overflows u64", total))
+}
+
+/// Chains ExactIn quotes through `pool_ab` then `pool_bc`, feeding the first output as the second input.
+/// The fees are aggregated in `in_mint`, the second hop fee converted at the first hop execution price,
+/// and `fee_pct` compounds both rates.
+pub fn quote_two_hop(
+    pool_ab: &OneIntroAmm,
+    pool_bc: &OneIntroAmm,
+    in_mint: Pubkey,
+    mid_mint: Pubkey,
+    out_mint: Pubkey,
+    amount: u64,
+) -> Result<Quote> {
+    let first = pool_ab.quote(&QuoteParams {
+        amount,
+        input_mint: in_mint,
+        output_mint: mid_mint,
+        swap_mode: SwapMode::ExactIn,
+    })?;
+    let second = pool_bc.quote(&QuoteParams {
+        amount: first.out_amount,
+        input_mint: mid_mint,
+        output_mint: out_mint,
+        swap_mode: SwapMode::ExactIn,
+    })?;
+
+    let second_fee_amount = proportional(second.fee_amount, first.in_amount, first.out_amount)?;
+
+    Ok(Quote {
+        in_amount: first.in_amount,
+        out_amount: second.out_amount,
+        fee_amount: sum_fee_amounts([first.fee_amount, second_fee_amount])?,
+        fee_mint: in_mint,
+        fee_pct: Decimal::ONE - (Decimal::ONE - first.fee_pct) * (Decimal::ONE - second.fee_pct),
+        not_enough_liquidity: first.not_enough_liquidity || second.not_enough_liquidity,
+        ..Quote::default()
+    })
+}
+
+// `quote_from_state` with an explicit swap fee rate, e.g. including the protocol fee, and an exit fee
+// retained from the output. `fee_amount` only reports the input fee.
+fn quote_state(
+    state: &PoolState,
+    input_mint: Pubkey,
+    output_mint: Pubkey,
+    amount: u64,
+    swap_mode: SwapMode,
+    swap_fee_rate: FeeRate,
+    exit_fee_rate: FeeRate,
+) -> Result<Quote> {
+    ensure_tradeable_token_count(state)?;
+    if input_mint == output_mint {
+        return Err(anyhow!("Cannot swap mint {} for itself", input_mint));
+    }
+
+    // Both records are matched by mint, so the quote does not depend on the token array order.
+    let token_in = token_record(state, &input_mint)?;
+    let token_out = token_record(state, &output_mint)?;
+
+    quote_records(token_in, token_out, swap_fee_rate, exit_fee_rate, amount, swap_mode)
+}
+
+fn quote_records(
+    token_in: &TokenRecord,
+    token_out: &TokenRecord,
+    swap_fee_rate: FeeRate,
+    exit_fee_rate: FeeRate,
+    amount: u64,
+    swap_mode: SwapMode,
+) -> Result<Quote> {
+    if token_in.weight == 0 || token_out.weight == 0 {
+        return Err(anyhow!("Cannot quote a swap involving a zero weight token"));
+    }
+
+    // Every `SwapMode` is matched without a wildcard on purpose, a new interface variant must fail to
+    // compile here instead of being quoted as one of the existing modes.
+    let (in_amount, out_amount, fee_amount, not_enough_liquidity) = match swap_mode {
+        SwapMode::ExactIn => {
+            swap_exact_amount_in(
+                token_in.typed_balance(),
+                token_in.typed_weight(),
+                token_out.typed_balance(),
+                token_out.typed_weight(),
+                amount,
+                swap_fee_rate,
+                exit_fee_rate,
+            )?
+        },
+        SwapMode::ExactOut => {
+            swap_exact_amount_out(
+                token_in.typed_balance(),
+                token_in.typed_weight(),
+                token_out.typed_balance(),
+                token_out.typed_weight(),
+                amount,
+                swap_fee_rate,
+                exit_fee_rate,
+            )?
+        },
+    };
+
+    Ok(Quote {
+        in_amount,
+        out_amount,
+        fee_amount,
+        fee_mint: token_in.mint_key,
+        fee_pct: swap_fee_rate.as_fraction(),
+        not_enough_liquidity,
+        ..Quote::default()
+    })
+}
+
 fn swap_exact_amount_in(
-    token_in_balance: u64,
-    token_in_weight: u64,
-    token_out_balance: u64,
-    token_out_weight: u64,
+    token_in_balance: Balance,
+    token_in_weight: Weight,
+    token_out_balance: Balance,
+    token_out_weight: Weight,
     token_in_amount: u64,
-    swap_fee_ratio: u64,
+    swap_fee_rate: FeeRate,
+    exit_fee_rate: FeeRate,
 ) -> Result<(u64, u64, u64, bool)> {
-    let max_token_in_amount = value_from_shares(MAX_IN_RATIO, token_in_balance, PONE)?;
+    let max_token_in_amount = token_in_balance.share(MAX_IN_RATIO)?;
 
-    let swap_fee_amount = value_from_shares(swap_fee_ratio, token_in_amount, PONE)?;
+    let swap_fee_amount = swap_fee_rate.apply(token_in_amount);
     let adjusted_token_in_amount = token_in_amount.checked_sub(swap_fee_amount).context("token_in_amount underflow")?;
+    if adjusted_token_in_amount == 0 {
+        return Err(into_anyhow(ErrorCode::ValidationTooSmallTokenInAmount));
+    }
 
+    // The program settles with the f64 formula, so it is always evaluated, this is not a shortcut. For an equal
+    // weight pair, in a 50/50 pool or any other since only the pair weight ratio enters the formula, the exact
+    // integer constant product is also computed and the lower of the two quoted. That drops the f64 rounding
+    // error when it is in the user's favour, and never promises more than the program pays out.
     let token_out_amount = calc_out_given_in(
         token_in_balance,
         token_in_weight,
         token_out_balance,
         token_out_weight,
         adjusted_token_in_amount,
-        0,
+        FeeRate::default(),
+        exit_fee_rate,
     )?;
+    let token_out_amount = if token_in_weight == token_out_weight {
+        calc_out_given_in_constant_product(token_in_balance, token_out_balance, adjusted_token_in_amount, exit_fee_rate)?
+            .min(token_out_amount)
+    } else {
+        token_out_amount
+    };
+
+    // A positive input that floors to zero output is dust, not a valid swap, unless there is no output to give.
+    if token_in_amount > 0 && token_out_amount == 0 {
+        if token_out_balance.0 == 0 {
+            return Ok((token_in_amount, 0, swap_fee_amount, true));
+        }
+        return Err(into_anyhow(ErrorCode::ValidationTooSmallTokenOutAmount));
+    }
+
+    // The output cannot exceed the balance mathematically, but f64 rounding near the cap could push it over.
+    let clamped_token_out_amount = token_out_amount.min(token_out_balance.0);
 
     Ok((
         token_in_amount,
-        token_out_amount,
+        clamped_token_out_amount,
         swap_fee_amount,
-        token_in_amount > max_token_in_amount,
+        token_in_amount > max_token_in_amount || clamped_token_out_amount < token_out_amount,
     ))
 }
 
 fn swap_exact_amount_out(
-    token_in_balance: u64,
-    token_in_weight: u64,
-    token_out_balance: u64,
-    token_out_weight: u64,
+    token_in_balance: Balance,
+    token_in_weight: Weight,
+    token_out_balance: Balance,
+    token_out_weight: Weight,
     token_out_amount: u64,
-    swap_fee_ratio: u64,
+    swap_fee_rate: FeeRate,
+    exit_fee_rate: FeeRate,
 ) -> Result<(u64, u64, u64, bool)> {
-    let max_token_out_amount = value_from_shares(MAX_OUT_RATIO, token_out_balance, PONE)?;
+    let max_token_out_amount = token_out_balance.share(MAX_OUT_RATIO)?;
+
+    // The pool must release enough before the exit fee for `token_out_amount` to remain after it.
+    let gross_token_out_amount = proportional_ceil(token_out_amount, PONE, PONE.checked_sub(exit_fee_rate.0).context("PONE underflow")?)?;
+
+    // No input buys the whole output balance or more. Such an output is quoted at the cap instead, flagged, so
+    // the quote still describes a swap the pool can fill. An empty output side fills nothing.
+    if gross_token_out_amount >= token_out_balance.0 && token_out_amount > max_token_out_amount {
+        if max_token_out_amount == 0 {
+            return Ok((0, 0, 0, true));
+        }
+        let (token_in_amount, token_out_amount, swap_fee_amount, _) = swap_exact_amount_out(
+            token_in_balance,
+            token_in_weight,
+            token_out_balance,
+            token_out_weight,
+            max_token_out_amount,
+            swap_fee_rate,
+            exit_fee_rate,
+        )?;
+        return Ok((token_in_amount, token_out_amount, swap_fee_amount, true));
+    }
 
     let temp_token_in_amount = calc_in_given_out(
         token_in_balance,
         token_in_weight,
         token_out_balance,
         token_out_weight,
-        token_out_amount,
-        0,
+        gross_token_out_amount,
+        FeeRate::default(),
     )?;
 
-    let token_in_amount = value_from_shares(PONE, temp_token_in_amount, PONE.checked_sub(swap_fee_ratio).context("PONE underflow")?)?;
+    // `calc_in_given_out` already rounds up, the fee gross-up rounds up too so the input never under-pays.
+    // With a zero fee ratio this is `temp * PONE / PONE`, which is exact.
+    let token_in_amount = proportional_ceil(temp_token_in_amount, PONE, PONE.checked_sub(swap_fee_rate.0).context("PONE underflow")?)?;
     let swap_fee_amount = token_in_amount.checked_sub(temp_token_in_amount).context("adjusted_token_in_amount underflow")?;
 
     Ok((
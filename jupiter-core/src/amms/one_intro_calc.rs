@@ -1,4 +1,8 @@
+// The math returns `anchor_lang::Result` like the on-chain program it mirrors, whose error type is large.
+#![allow(clippy::result_large_err)]
+
 use anchor_lang::prelude::*;
+use rust_decimal::Decimal;
 use safemath::*;
 
 pub const PONE: u64 = 1_000_000_000;
@@ -6,13 +10,101 @@ pub const PONE: u64 = 1_000_000_000;
 pub const MAX_IN_RATIO: u64 = PONE / 2; // 50%
 pub const MAX_OUT_RATIO: u64 = PONE / 2; // 50%
 
+// Balances and amounts up to 2^53 convert to f64 exactly. Above it the calc functions stay accurate to the
+// f64 relative precision (about 1e-16 of the balance), and results that do not fit a u64 are a `CalculationFailure`.
+pub const MAX_PRECISE_BALANCE: u64 = 1 << 53;
+
+// Weight ratios outside this range go through `pow_minus_one` to keep f64 precision.
+pub const PRECISE_POW_WEIGHT_RATIO_MIN: f64 = 0.1;
+pub const PRECISE_POW_WEIGHT_RATIO_MAX: f64 = 10.0;
+
 #[error_code]
+#[derive(PartialEq, Eq)]
 pub enum ErrorCode {
     #[msg("Calculation: general failure")]
     CalculationFailure,
 
     #[msg("Transaction Failed: Output token exceeds 50% of the token in pool liquidity. Reduce and retry.")]
     ValidationLiquidityTooBigTokenOutAmount,
+
+    #[msg("Validation: Input token amount is too small after the swap fee. Increase and retry.")]
+    ValidationTooSmallTokenInAmount,
+
+    #[msg("Validation: Output token amount is too small. Increase the input and retry.")]
+    ValidationTooSmallTokenOutAmount,
+
+    #[msg("Validation: Not enough liquidity in the pool for this swap.")]
+    NotEnoughLiquidity,
+
+    #[msg("Validation: Mint is not a token of this pool.")]
+    MintNotInPool,
+}
+
+impl ErrorCode {
+    pub const ALL: [ErrorCode; 6] = [
+        ErrorCode::CalculationFailure,
+        ErrorCode::ValidationLiquidityTooBigTokenOutAmount,
+        ErrorCode::ValidationTooSmallTokenInAmount,
+        ErrorCode::ValidationTooSmallTokenOutAmount,
+        ErrorCode::NotEnoughLiquidity,
+        ErrorCode::MintNotInPool,
+    ];
+
+    pub fn from_error_code_number(error_code_number: u32) -> Option<ErrorCode> {
+        ErrorCode::ALL.into_iter().find(|v| u32::from(*v) == error_code_number)
+    }
+}
+
+/// A fee expressed relative to `PONE`, e.g. `FeeRate(3_000_000)` is 0.3%
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FeeRate(pub u64);
+
+impl FeeRate {
+    pub fn as_fraction(&self) -> Decimal {
+        Decimal::from(self.0) / Decimal::from(PONE)
+    }
+
+    pub fn as_bps(&self) -> u64 {
+        (self.0 as u128 * 10_000 / PONE as u128) as u64
+    }
+
+    /// The fee charged on `amount`, rounded down
+    pub fn apply(&self, amount: u64) -> u64 {
+        u64::try_from(amount as u128 * self.0 as u128 / PONE as u128).unwrap_or(u64::MAX)
+    }
+}
+
+/// An amount in raw token base units, e.g. a pool balance. Unlike fees and ratios it is never scaled by `PONE`,
+/// and it does not convert to or from a `Weight`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Balance(pub u64);
+
+impl Balance {
+    /// `ratio` of this balance, rounded down, the ratio being relative to `PONE` such as `MAX_IN_RATIO`
+    pub fn share(&self, ratio: u64) -> anchor_lang::Result<u64> {
+        value_from_shares(ratio, self.0, PONE)
+    }
+}
+
+/// A token weight. Only the ratio of two weights enters the math, the pool stores them relative to
+/// `pool_token_total_weight`, which need not be `PONE`. `normalized` is the one place they are scaled to it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Weight(pub u64);
+
+impl Weight {
+    /// This weight divided by `other`, `None` when `other` is zero
+    pub fn ratio_to(&self, other: Weight) -> Option<Decimal> {
+        Decimal::from(self.0).checked_div(Decimal::from(other.0))
+    }
+
+    /// This weight's share of `total_weight` relative to `PONE`, rounded down
+    pub fn normalized(&self, total_weight: Weight) -> anchor_lang::Result<u64> {
+        if total_weight.0 == 0 {
+            return Err(ErrorCode::CalculationFailure.into());
+        }
+
+        proportional(self.0, PONE, total_weight.0)
+    }
 }
 
 pub fn proportional(amount: u64, numerator: u64, denominator: u64) -> anchor_lang::Result<u64> {
@@ -29,6 +121,20 @@ pub fn proportional(amount: u64, numerator: u64, denominator: u64) -> anchor_lan
     u64::try_from(value).map_err(|_| ErrorCode::CalculationFailure.into())
 }
 
+/// `proportional` rounded up, for amounts the pool must never under-collect
+pub fn proportional_ceil(amount: u64, numerator: u64, denominator: u64) -> anchor_lang::Result<u64> {
+    if denominator == 0 {
+        return Ok(amount);
+    }
+
+    let value = (amount as u128)
+        .checked_mul(numerator as u128)
+        .ok_or::<anchor_lang::error::Error>(ErrorCode::CalculationFailure.into())?
+        .div_ceil(denominator as u128);
+
+    u64::try_from(value).map_err(|_| ErrorCode::CalculationFailure.into())
+}
+
 pub fn value_from_shares(shares: u64, total_value: u64, total_shares: u64) -> anchor_lang::Result<u64> {
     proportional(shares, total_value, total_shares)
 }
@@ -44,17 +150,26 @@ pub enum RoundDirection {
 **********************************************************************************************/
 
 pub mod safemath {
-    use super::RoundDirection;
+    use super::{ErrorCode, RoundDirection};
+
+    // 2^64, the first f64 that does not fit a u64. Casting it or anything above would silently saturate.
+    const U64_OVERFLOW_F64: f64 = 18_446_744_073_709_551_616.0;
 
     pub fn u64_to_f64_unchecked(value: u64) -> f64 {
         value as f64
     }
 
-    pub fn f64_to_u64_rounded(value: f64, rounding: RoundDirection) -> u64 {
-        match rounding {
-            RoundDirection::Floor => value.floor() as u64,
-            RoundDirection::Ceiling => value.ceil() as u64,
+    // Negative values are rejected even when they would round up to zero. The range is checked after rounding,
+    // so a ceiling just under 2^64 that rounds up to it is rejected too, and NaN and infinities fail it.
+    pub fn f64_to_u64_rounded(value: f64, rounding: RoundDirection) -> anchor_lang::Result<u64> {
+        let rounded = match rounding {
+            RoundDirection::Floor => value.floor(),
+            RoundDirection::Ceiling => value.ceil(),
+        };
+        if value < 0.0 || !(0.0..U64_OVERFLOW_F64).contains(&rounded) {
+            return Err(ErrorCode::CalculationFailure.into());
         }
+        Ok(rounded as u64)
     }
 
     pub fn add(left: f64, right: f64) -> f64 {
@@ -73,9 +188,120 @@ pub mod safemath {
         left / right
     }
 
+    // `powf`, `ln` and `exp` come from the platform libm and may differ in the last bits between targets.
+    // The functions below only use IEEE 754 basic operations, which are correctly rounded everywhere, and a
+    // fixed number of series terms, so a validator and a client compute bit-identical quotes.
+    // Against `powf`, `pow` and `pow_minus_one` stay within 1e-15 * (1 + |n * ln(base)|) relative, the error
+    // of `ln` growing with the exponent as it does in any exp/ln pow. Subnormal results lose precision.
+
+    // ln(2) split so `k * LN_2_HI` is exact for any exponent `k`, as in fdlibm
+    const LN_2_HI: f64 = f64::from_bits(0x3fe6_2e42_fee0_0000);
+    const LN_2_LO: f64 = f64::from_bits(0x3dea_39ef_3579_3c76);
+
     pub fn pow(base: f64, n: f64) -> f64 {
-        base.powf(n)
+        if base == 1.0 || n == 0.0 {
+            return 1.0;
+        }
+        exp(n * ln(base))
     }
+
+    // (1 + x) ^ n - 1, without the cancellation `pow` suffers when the result is close to 1
+    pub fn pow_minus_one(x: f64, n: f64) -> f64 {
+        exp_m1(n * ln_1p(x))
+    }
+
+    pub fn ln(x: f64) -> f64 {
+        if x.is_nan() || x < 0.0 {
+            return f64::NAN;
+        }
+        if x == 0.0 {
+            return f64::NEG_INFINITY;
+        }
+        if x.is_infinite() {
+            return f64::INFINITY;
+        }
+
+        // x = m * 2^k with m in [sqrt(1/2), sqrt(2)), subnormals scaled up by 2^54 first
+        let (x, scale) = if x < f64::MIN_POSITIVE { (x * 18_014_398_509_481_984.0, -54) } else { (x, 0) };
+        let bits = x.to_bits();
+        let mut k = ((bits >> 52) & 0x7ff) as i64 - 1023 + scale;
+        let mut m = f64::from_bits((bits & 0x000f_ffff_ffff_ffff) | 0x3ff0_0000_0000_0000);
+        if m > std::f64::consts::SQRT_2 {
+            m /= 2.0;
+            k += 1;
+        }
+
+        // ln(m) = 2 * atanh(s) with s = (m - 1) / (m + 1), |s| <= 0.172, so 12 odd terms are below 1e-17
+        let s = (m - 1.0) / (m + 1.0);
+        let s2 = s * s;
+        let mut term = s;
+        let mut series = 0.0;
+        for i in 0..12 {
+            series += term / (2 * i + 1) as f64;
+            term *= s2;
+        }
+
+        let k = k as f64;
+        k * LN_2_HI + (2.0 * series + k * LN_2_LO)
+    }
+
+    // ln(1 + x), exact for `x` too small to change `1 + x`, then corrected for the rounding of `1 + x`
+    pub fn ln_1p(x: f64) -> f64 {
+        let u = 1.0 + x;
+        if u == 1.0 {
+            return x;
+        }
+        if u.is_infinite() {
+            return f64::INFINITY;
+        }
+        ln(u) * (x / (u - 1.0))
+    }
+
+    pub fn exp(x: f64) -> f64 {
+        if x.is_nan() {
+            return f64::NAN;
+        }
+        if x > 709.782_712_893_384 {
+            return f64::INFINITY;
+        }
+        if x < -745.133_219_101_941_1 {
+            return 0.0;
+        }
+
+        // x = k * ln(2) + r with |r| <= ln(2) / 2, exp(r) from the Taylor series, then scaled by 2^k
+        let k = (x / std::f64::consts::LN_2).round();
+        let r = (x - k * LN_2_HI) - k * LN_2_LO;
+
+        scale_by_power_of_two(1.0 + taylor_exp_m1(r), k as i32)
+    }
+
+    // exp(x) - 1, from the Taylor series up to |x| = 1 where `exp(x) - 1` would cancel
+    pub fn exp_m1(x: f64) -> f64 {
+        if x.abs() <= 1.0 {
+            return taylor_exp_m1(x);
+        }
+        exp(x) - 1.0
+    }
+
+    // Up to x^19 / 19!, the next term is below 1e-18 for |x| <= 1. Evaluated in Horner form, smallest terms first.
+    fn taylor_exp_m1(x: f64) -> f64 {
+        let mut series = 1.0;
+        for i in (2..20).rev() {
+            series = 1.0 + series * x / i as f64;
+        }
+        x * series
+    }
+
+    // value * 2^k, in two steps so neither factor overflows or underflows on its own
+    fn scale_by_power_of_two(value: f64, k: i32) -> f64 {
+        let half = k / 2;
+        let power_of_two = |e: i32| f64::from_bits(((e + 1023) as u64) << 52);
+        value * power_of_two(half) * power_of_two(k - half)
+    }
+}
+
+fn is_extreme_weight_ratio(weight_ratio: f64) -> bool {
+    !(PRECISE_POW_WEIGHT_RATIO_MIN..=PRECISE_POW_WEIGHT_RATIO_MAX).contains(&weight_ratio)
 }
 
 /**********************************************************************************************
@@ -89,33 +315,68 @@ pub mod safemath {
 // sF = swapFee                                                                              //
 **********************************************************************************************/
 pub fn calc_out_given_in(
-    token_in_balance: u64,
-    token_in_weight: u64,
-    token_out_balance: u64,
-    token_out_weight: u64,
+    token_in_balance: Balance,
+    token_in_weight: Weight,
+    token_out_balance: Balance,
+    token_out_weight: Weight,
     token_in_amount: u64,
-    swap_fee: u64,
+    swap_fee: FeeRate,
+    exit_fee: FeeRate,
 ) -> anchor_lang::Result<u64> {
-    let token_in_balance_f64 = u64_to_f64_unchecked(token_in_balance);
-    let total_in_weight_f64 = u64_to_f64_unchecked(token_in_weight);
-    let token_out_balance_f64 = u64_to_f64_unchecked(token_out_balance);
-    let token_out_weight_f64 = u64_to_f64_unchecked(token_out_weight);
+    let token_in_balance_f64 = u64_to_f64_unchecked(token_in_balance.0);
+    let total_in_weight_f64 = u64_to_f64_unchecked(token_in_weight.0);
+    let token_out_balance_f64 = u64_to_f64_unchecked(token_out_balance.0);
+    let token_out_weight_f64 = u64_to_f64_unchecked(token_out_weight.0);
     let token_in_amount_f64 = u64_to_f64_unchecked(token_in_amount);
-    let swap_fee_f64 = u64_to_f64_unchecked(swap_fee);
+    let swap_fee_f64 = u64_to_f64_unchecked(swap_fee.0);
+    let exit_fee_f64 = u64_to_f64_unchecked(exit_fee.0);
 
+    // Weights only enter as a ratio, so raw weights and weights normalized against
+    // `pool_token_total_weight` quote identically.
     let weight_ratio = div(total_in_weight_f64, token_out_weight_f64);
     let adjusted_in = mul(
         token_in_amount_f64,
-        sub(1.0 as f64, div(swap_fee_f64, PONE as f64)),
+        sub(1.0, div(swap_fee_f64, PONE as f64)),
     );
-    let y = div(token_in_balance_f64, add(token_in_balance_f64, adjusted_in));
-    let foo = pow(y, weight_ratio);
-    let bar = sub(1.0 as f64, foo);
+    let bar = if is_extreme_weight_ratio(weight_ratio) {
+        let y_minus_one = div(-adjusted_in, add(token_in_balance_f64, adjusted_in));
+        -pow_minus_one(y_minus_one, weight_ratio)
+    } else {
+        let y = div(token_in_balance_f64, add(token_in_balance_f64, adjusted_in));
+        let power = pow(y, weight_ratio);
+        sub(1.0, power)
+    };
 
-    Ok(f64_to_u64_rounded(
+    // The exit fee is retained from the output, on top of the swap fee taken from the input.
+    let token_out_amount_f64 = mul(
         mul(token_out_balance_f64, bar),
-        RoundDirection::Floor,
-    ))
+        sub(1.0, div(exit_fee_f64, PONE as f64)),
+    );
+
+    f64_to_u64_rounded(token_out_amount_f64, RoundDirection::Floor)
+}
+
+/// `calc_out_given_in` for equal weights, where it reduces to the constant product
+/// `aO = bO * aI / (bI + aI)`, computed exactly in integers instead of through the f64 `pow`.
+/// The exit fee is retained afterwards, both steps rounding down. Quotes take the lower of this and
+/// `calc_out_given_in`, so they never exceed what the program's f64 math pays out.
+pub fn calc_out_given_in_constant_product(
+    token_in_balance: Balance,
+    token_out_balance: Balance,
+    token_in_amount: u64,
+    exit_fee: FeeRate,
+) -> anchor_lang::Result<u64> {
+    let denominator = (token_in_balance.0 as u128)
+        .checked_add(token_in_amount as u128)
+        .ok_or::<anchor_lang::error::Error>(ErrorCode::CalculationFailure.into())?;
+    if denominator == 0 {
+        return Err(ErrorCode::CalculationFailure.into());
+    }
+    let token_out_amount = u64::try_from(token_out_balance.0 as u128 * token_in_amount as u128 / denominator)
+        .map_err(|_| ErrorCode::CalculationFailure)?;
+
+    let exit_fee_complement = PONE.checked_sub(exit_fee.0).ok_or(ErrorCode::CalculationFailure)?;
+    proportional(token_out_amount, exit_fee_complement, PONE)
 }
 
 /**********************************************************************************************
@@ -129,30 +390,38 @@ pub fn calc_out_given_in(
 // sF = swapFee                                                                              //
 **********************************************************************************************/
 pub fn calc_in_given_out(
-    token_in_balance: u64,
-    token_in_weight: u64,
-    token_out_balance: u64,
-    token_out_weight: u64,
+    token_in_balance: Balance,
+    token_in_weight: Weight,
+    token_out_balance: Balance,
+    token_out_weight: Weight,
     token_out_amount: u64,
-    swap_fee: u64,
+    swap_fee: FeeRate,
 ) -> anchor_lang::Result<u64> {
-    let token_in_balance_f64 = u64_to_f64_unchecked(token_in_balance);
-    let total_in_weight_f64 = u64_to_f64_unchecked(token_in_weight);
-    let token_out_balance_f64 = u64_to_f64_unchecked(token_out_balance);
-    let token_out_weight_f64 = u64_to_f64_unchecked(token_out_weight);
+    let token_in_balance_f64 = u64_to_f64_unchecked(token_in_balance.0);
+    let total_in_weight_f64 = u64_to_f64_unchecked(token_in_weight.0);
+    let token_out_balance_f64 = u64_to_f64_unchecked(token_out_balance.0);
+    let token_out_weight_f64 = u64_to_f64_unchecked(token_out_weight.0);
     let token_out_amount_f64 = u64_to_f64_unchecked(token_out_amount);
-    let swap_fee_f64 = u64_to_f64_unchecked(swap_fee);
+    let swap_fee_f64 = u64_to_f64_unchecked(swap_fee.0);
 
+    // Scale-invariant in the weights, see `calc_out_given_in`.
     let weight_ratio = div(token_out_weight_f64, total_in_weight_f64);
     let diff = sub(token_out_balance_f64, token_out_amount_f64);
-    let y = div(token_out_balance_f64, diff);
-    let foo = sub(pow(y, weight_ratio), 1.0 as f64);
-
-    Ok(f64_to_u64_rounded(
-        div(
-            mul(token_in_balance_f64, foo),
-            sub(1.0 as f64, div(swap_fee_f64, PONE as f64)),
-        ),
-        RoundDirection::Ceiling,
-    ))
+    let power_minus_one = if is_extreme_weight_ratio(weight_ratio) {
+        pow_minus_one(div(token_out_amount_f64, diff), weight_ratio)
+    } else {
+        let y = div(token_out_balance_f64, diff);
+        sub(pow(y, weight_ratio), 1.0)
+    };
+    // An output close to the whole balance with a large weight ratio overflows the power to infinity.
+    if !power_minus_one.is_finite() {
+        return Err(ErrorCode::CalculationFailure.into());
+    }
+
+    let token_in_amount_f64 = div(
+        mul(token_in_balance_f64, power_minus_one),
+        sub(1.0, div(swap_fee_f64, PONE as f64)),
+    );
+
+    f64_to_u64_rounded(token_in_amount_f64, RoundDirection::Ceiling)
 }
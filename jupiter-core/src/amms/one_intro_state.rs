@@ -1,9 +1,23 @@
 use anchor_lang::prelude::*;
 use anchor_lang::{AnchorDeserialize, AnchorSerialize};
-use solana_sdk::pubkey::Pubkey;
+use solana_sdk::{pubkey, pubkey::Pubkey};
+
+use super::one_intro_calc::{Balance, Weight};
 
 pub const MAX_TOKEN_COUNT: usize = 4;
 
+// Unused slots of the token array are filled with the system program id.
+pub const EMPTY_TOKEN_MINT: Pubkey = pubkey!("11111111111111111111111111111111");
+
+// sha256("account:PoolState")[..8], the Anchor discriminator telling pool states apart from other program accounts.
+pub const POOL_STATE_DISCRIMINATOR: [u8; 8] = [247, 237, 227, 245, 215, 195, 222, 70];
+
+// sha256("account:MetadataState")[..8], for the global account holding the protocol fee settings.
+pub const METADATA_STATE_DISCRIMINATOR: [u8; 8] = [74, 68, 237, 189, 45, 190, 195, 21];
+
+pub const TOKEN_RECORD_LEN: usize = 32 + 32 + 8 + 8;
+pub const POOL_STATE_LEN: usize = 32 + 1 + 32 + 8 + 8 + TOKEN_RECORD_LEN * MAX_TOKEN_COUNT + 8 + 8;
+
 #[derive(AnchorSerialize, AnchorDeserialize, PartialEq, Debug, Clone, Copy)]
 #[repr(C)]
 pub struct TokenRecord {
@@ -13,6 +27,17 @@ pub struct TokenRecord {
     pub weight: u64,
 }
 
+impl TokenRecord {
+    // The fields keep the on-chain layout, the math goes through these to not mix the two units up.
+    pub fn typed_balance(&self) -> Balance {
+        Balance(self.balance)
+    }
+
+    pub fn typed_weight(&self) -> Weight {
+        Weight(self.weight)
+    }
+}
+
 #[derive(AnchorSerialize, AnchorDeserialize, PartialEq, Debug, Clone, Copy)]
 pub struct PoolState {
     // one unique authority PDA
@@ -23,7 +48,7 @@ pub struct PoolState {
     pub pool_lp_mint_key: Pubkey,
     pub pool_lp_virtual_supply: u64,
 
-    // pool token list
+    // pool token list, weights are relative to each other and sum to the total weight
     pub pool_token_count: u64,
     pub pool_token_array: [TokenRecord; MAX_TOKEN_COUNT],
     pub pool_token_total_weight: u64,
@@ -31,3 +56,27 @@ pub struct PoolState {
     // swap fee
     pub pool_swap_fee_ratio: u64,
 }
+
+impl PoolState {
+    pub fn active_token_records(&self) -> impl Iterator<Item = &TokenRecord> {
+        self.pool_token_array
+            .iter()
+            .filter(|v| !v.mint_key.eq(&EMPTY_TOKEN_MINT))
+    }
+
+    pub fn token_record(&self, mint: &Pubkey) -> Option<&TokenRecord> {
+        self.active_token_records().find(|v| v.mint_key.eq(mint))
+    }
+}
+
+// No IDL of the metadata state ships with this repo, so this layout is an assumption: the admin key followed by
+// the protocol fee ratio the swap charges, relative to `PONE`. Only these leading fields are modeled, any data
+// after them is ignored. Parsing checks `METADATA_STATE_DISCRIMINATOR` and the update bounds the resulting fee,
+// so an account of another shape fails the update instead of skewing quotes. Confirm against the program IDL.
+#[derive(AnchorSerialize, AnchorDeserialize, PartialEq, Debug, Clone, Copy)]
+pub struct MetadataState {
+    pub admin_auth_key: Pubkey,
+
+    // protocol fee, charged on the input on top of the pool swap fee
+    pub protocol_swap_fee_ratio: u64,
+}
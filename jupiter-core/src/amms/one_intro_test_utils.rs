@@ -0,0 +1,67 @@
+use jupiter_amm_interface::{QuoteParams, SwapMode, SwapParams};
+use solana_sdk::{pubkey, pubkey::Pubkey};
+use spl_associated_token_account::get_associated_token_address;
+
+use super::one_intro_state::{PoolState, TokenRecord};
+
+const JUPITER_PROGRAM_ID: Pubkey = pubkey!("JUP6LkbZbjS1jKKwapdHNy74zcZ3tLUZoi5QNyVTaV4");
+
+/// Builds `QuoteParams` and `SwapParams` for a swap between two tokens of a pool state
+pub struct SwapFixture {
+    pub input: TokenRecord,
+    pub output: TokenRecord,
+    pub user: Pubkey,
+    pub source_token_account: Pubkey,
+    pub destination_token_account: Pubkey,
+}
+
+impl SwapFixture {
+    /// Swaps `pool_token_array[input_index]` for `pool_token_array[output_index]` from a fresh user's ATAs
+    pub fn new(state: &PoolState, input_index: usize, output_index: usize) -> Self {
+        let input = state.pool_token_array[input_index];
+        let output = state.pool_token_array[output_index];
+        let user = Pubkey::new_unique();
+
+        SwapFixture {
+            input,
+            output,
+            user,
+            source_token_account: get_associated_token_address(&user, &input.mint_key),
+            destination_token_account: get_associated_token_address(&user, &output.mint_key),
+        }
+    }
+
+    pub fn reversed(&self) -> Self {
+        SwapFixture {
+            input: self.output,
+            output: self.input,
+            user: self.user,
+            source_token_account: self.destination_token_account,
+            destination_token_account: self.source_token_account,
+        }
+    }
+
+    pub fn quote_params(&self, amount: u64, swap_mode: SwapMode) -> QuoteParams {
+        QuoteParams {
+            amount,
+            input_mint: self.input.mint_key,
+            output_mint: self.output.mint_key,
+            swap_mode,
+        }
+    }
+
+    pub fn swap_params(&self, in_amount: u64, out_amount: u64) -> SwapParams<'static, 'static> {
+        SwapParams {
+            in_amount,
+            out_amount,
+            source_mint: self.input.mint_key,
+            destination_mint: self.output.mint_key,
+            source_token_account: self.source_token_account,
+            destination_token_account: self.destination_token_account,
+            token_transfer_authority: self.user,
+            open_order_address: None,
+            quote_mint_to_referrer: None,
+            jupiter_program_id: &JUPITER_PROGRAM_ID,
+        }
+    }
+}
@@ -1,4 +1,6 @@
 pub mod amms;
+// SPL token-swap helpers, the example AMM does not use all of them
+#[allow(dead_code)]
 mod math;
 
 pub mod build_swap_transaction;
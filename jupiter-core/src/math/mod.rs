@@ -1,3 +1,5 @@
+#[cfg(feature = "spl-token-swap")]
 pub mod fees;
+#[cfg(feature = "spl-token-swap")]
 pub mod swap_curve_info;
 pub mod token_swap;
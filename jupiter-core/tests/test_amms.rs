@@ -7,10 +7,12 @@ use jupiter_core::amms::one_intro_amm::OneIntroAmm;
 // use jupiter_core::amms::openbook_v2_amm::OpenBookV2Amm;
 use jupiter_core::{
     amm::Amm,
-    amms::{spl_token_swap_amm::SplTokenSwapAmm, test_harness::AmmTestHarness},
+    amms::test_harness::AmmTestHarness,
     route::get_token_mints_permutations,
     test_harness::AmmTestSwapParams,
 };
+#[cfg(feature = "spl-token-swap")]
+use jupiter_core::amms::spl_token_swap_amm::SplTokenSwapAmm;
 use solana_sdk::pubkey::Pubkey;
 use solana_sdk::{account::Account, pubkey};
 
@@ -105,15 +107,21 @@ macro_rules! test_exact_out_amms {
     };
 }
 
+#[cfg(feature = "spl-token-swap")]
 const ORCA_V2_SOL_USDC_POOL: Pubkey = pubkey!("EGZ7tiLeH62TPV1gL8WwbXGzEPa9zmcpVnnkPKKnrE2U");
+#[cfg(feature = "spl-token-swap")]
 const ORCA_V2_USDC_USDT_POOL: Pubkey = pubkey!("F13xvvx45jVGd84ynK3c8T89UejQVxjCLtmHfPmAXAHP");
 const ONE_INTRO_INTRO_SOL_POOL: Pubkey = pubkey!("2Jfgo47Xk5JLqKLLWvSGFPaxGRcY7QNE4GgB3WHVtYUk");
 
 // You can run a single test by doing: `cargo test test_quote_<lower_case_constant>_<default | option_name> -- --nocapture`
 
+#[cfg(feature = "spl-token-swap")]
 test_exact_in_amms! {
     (ORCA_V2_SOL_USDC_POOL, SplTokenSwapAmm, 0),
     (ORCA_V2_USDC_USDT_POOL, SplTokenSwapAmm, 0),
+}
+
+test_exact_in_amms! {
     (ONE_INTRO_INTRO_SOL_POOL, OneIntroAmm, 0),
 }
 
@@ -0,0 +1,2511 @@
+use std::collections::HashMap;
+
+use anchor_lang::{solana_program::hash::hash, AnchorDeserialize, AnchorSerialize};
+use jupiter_amm_interface::{AccountMap, Amm, KeyedAccount, Quote, QuoteParams, SwapMode, SwapParams};
+use jupiter_core::amms::{
+    loader::{amm_factory, register_amm, AmmConstructor, AmmRegistry},
+    one_intro_amm::{
+        convert_anchor_error, invariant_ln, minimum_out_amount, quote_from_state, quote_raw, quote_two_hop, total_fee_amount,
+        AccountUpdateHint, Balance, ErrorCode, FeeRate, FeeUnit, OneIntroAmm, PoolMetrics, PoolQuoter, QuoteFeeComponents, RawQuote, ReferredQuote, RoundingPolicy, SwapDirection,
+        SwapInstructionArgs, TokenMetrics, TransferAuthority, Weight, WrappedSolStep, MAX_PRECISE_BALANCE, ONE_INTRO_METADATA_STATE, ONE_INTRO_PROGRAM_ID,
+        POOL_AUTH_PDA_SEED,
+    },
+    one_intro_state::{
+        MetadataState, PoolState, TokenRecord, EMPTY_TOKEN_MINT, MAX_TOKEN_COUNT, METADATA_STATE_DISCRIMINATOR,
+        POOL_STATE_DISCRIMINATOR, POOL_STATE_LEN,
+    },
+    one_intro_test_utils::SwapFixture,
+};
+use rust_decimal::Decimal;
+use solana_sdk::{account::Account, pubkey, pubkey::Pubkey};
+
+const PONE: u64 = 1_000_000_000;
+const JUPITER_PROGRAM_ID: Pubkey = pubkey!("JUP6LkbZbjS1jKKwapdHNy74zcZ3tLUZoi5QNyVTaV4");
+
+fn empty_record() -> TokenRecord {
+    TokenRecord {
+        mint_key: EMPTY_TOKEN_MINT,
+        account_key: EMPTY_TOKEN_MINT,
+        balance: 0,
+        weight: 0,
+    }
+}
+
+fn record(balance: u64, weight: u64) -> TokenRecord {
+    TokenRecord {
+        mint_key: Pubkey::new_unique(),
+        account_key: Pubkey::new_unique(),
+        balance,
+        weight,
+    }
+}
+
+fn pool_state(records: &[TokenRecord], swap_fee_ratio: u64) -> PoolState {
+    let mut pool_token_array = [empty_record(); MAX_TOKEN_COUNT];
+    pool_token_array[..records.len()].copy_from_slice(records);
+
+    PoolState {
+        pool_auth_pda_key: Pubkey::new_unique(),
+        pool_auth_pda_bump: 255,
+        pool_lp_mint_key: Pubkey::new_unique(),
+        pool_lp_virtual_supply: 1_000_000_000,
+        pool_token_count: records.len() as u64,
+        pool_token_array,
+        pool_token_total_weight: records.iter().map(|v| v.weight).sum(),
+        pool_swap_fee_ratio: swap_fee_ratio,
+    }
+}
+
+fn account_data(state: &PoolState) -> Vec<u8> {
+    let mut data = vec![0u8; 8]; // Anchor discriminator
+    state.serialize(&mut data).unwrap();
+    data
+}
+
+fn keyed_account(state: &PoolState) -> KeyedAccount {
+    KeyedAccount {
+        key: Pubkey::new_unique(),
+        account: Account {
+            lamports: 1_000_000,
+            data: account_data(state),
+            owner: ONE_INTRO_PROGRAM_ID,
+            executable: false,
+            rent_epoch: 0,
+        },
+        params: None,
+    }
+}
+
+fn amm(state: &PoolState) -> OneIntroAmm {
+    OneIntroAmm::from_keyed_account(&keyed_account(state)).unwrap()
+}
+
+fn quote_params(input: &TokenRecord, output: &TokenRecord, amount: u64, swap_mode: SwapMode) -> QuoteParams {
+    QuoteParams {
+        amount,
+        input_mint: input.mint_key,
+        output_mint: output.mint_key,
+        swap_mode,
+    }
+}
+
+fn swap_params(input: &TokenRecord, output: &TokenRecord, in_amount: u64, out_amount: u64) -> SwapParams<'static, 'static> {
+    SwapParams {
+        in_amount,
+        out_amount,
+        source_mint: input.mint_key,
+        destination_mint: output.mint_key,
+        source_token_account: Pubkey::new_unique(),
+        destination_token_account: Pubkey::new_unique(),
+        token_transfer_authority: Pubkey::new_unique(),
+        open_order_address: None,
+        quote_mint_to_referrer: None,
+        jupiter_program_id: &JUPITER_PROGRAM_ID,
+    }
+}
+
+#[test]
+fn test_quote_dust_input_rounding_to_zero_output_is_rejected() {
+    let token_in = record(1_000_000_000_000, PONE / 2);
+    let token_out = record(1_000_000, PONE / 2);
+    let amm = amm(&pool_state(&[token_in, token_out], 0));
+
+    let result = amm.quote(&quote_params(&token_in, &token_out, 1, SwapMode::ExactIn));
+    assert!(result.is_err());
+
+    let quote = amm
+        .quote(&quote_params(&token_in, &token_out, 1_000_000_000, SwapMode::ExactIn))
+        .unwrap();
+    assert!(quote.out_amount > 0);
+}
+
+#[test]
+fn test_quote_is_invariant_to_weight_scaling() {
+    let (balance_in, balance_out) = (5_000_000_000, 20_000_000_000);
+    let mint_in = Pubkey::new_unique();
+    let mint_out = Pubkey::new_unique();
+    let scaled = |weight_in: u64, weight_out: u64| {
+        let mut token_in = record(balance_in, weight_in);
+        let mut token_out = record(balance_out, weight_out);
+        token_in.mint_key = mint_in;
+        token_out.mint_key = mint_out;
+        (amm(&pool_state(&[token_in, token_out], 3_000_000)), token_in, token_out)
+    };
+
+    let (raw_amm, token_in, token_out) = scaled(2 * PONE, 8 * PONE);
+    let (normalized_amm, _, _) = scaled(PONE / 5, 4 * PONE / 5);
+
+    for swap_mode in [SwapMode::ExactIn, SwapMode::ExactOut] {
+        let params = quote_params(&token_in, &token_out, 100_000_000, swap_mode);
+        let raw = raw_amm.quote(&params).unwrap();
+        let normalized = normalized_amm.quote(&params).unwrap();
+
+        assert_eq!(raw.in_amount, normalized.in_amount);
+        assert_eq!(raw.out_amount, normalized.out_amount);
+        assert_eq!(raw.fee_amount, normalized.fee_amount);
+    }
+}
+
+#[test]
+fn test_swap_instruction_data() {
+    let token_in = record(1_000_000_000, PONE / 2);
+    let token_out = record(1_000_000_000, PONE / 2);
+    let amm = amm(&pool_state(&[token_in, token_out], 3_000_000));
+
+    let instruction = amm
+        .swap_instruction(&swap_params(&token_in, &token_out, 1_000, 900), 900)
+        .unwrap();
+
+    assert_eq!(instruction.program_id, ONE_INTRO_PROGRAM_ID);
+    assert_eq!(instruction.data[..8], hash(b"global:swap").to_bytes()[..8]);
+    assert_eq!(
+        SwapInstructionArgs::deserialize(&mut &instruction.data[8..]).unwrap(),
+        SwapInstructionArgs {
+            token_in_amount: 1_000,
+            minimum_token_out_amount: 900,
+        }
+    );
+    assert_eq!(instruction.accounts.len(), 11);
+}
+
+#[test]
+fn test_quote_from_state_matches_amm_quote() {
+    let token_in = record(3_000_000_000, PONE / 2);
+    let token_out = record(7_000_000_000, PONE / 2);
+    let state = pool_state(&[token_in, token_out], 3_000_000);
+    let amm = amm(&state);
+
+    for swap_mode in [SwapMode::ExactIn, SwapMode::ExactOut] {
+        let quote = quote_from_state(&state, token_in.mint_key, token_out.mint_key, 10_000_000, swap_mode).unwrap();
+        let amm_quote = amm
+            .quote(&quote_params(&token_in, &token_out, 10_000_000, swap_mode))
+            .unwrap();
+
+        assert_eq!(quote.in_amount, amm_quote.in_amount);
+        assert_eq!(quote.out_amount, amm_quote.out_amount);
+        assert_eq!(quote.fee_amount, amm_quote.fee_amount);
+        assert_eq!(quote.fee_mint, token_in.mint_key);
+        match swap_mode {
+            SwapMode::ExactIn => assert_eq!(quote.in_amount, 10_000_000),
+            SwapMode::ExactOut => assert_eq!(quote.out_amount, 10_000_000),
+        }
+    }
+}
+
+#[test]
+fn test_quote_zero_fee_pool_charges_no_fee() {
+    let token_a = record(10_000_000_000, PONE / 2);
+    let token_b = record(10_000_000_000, PONE / 2);
+    let amm = amm(&pool_state(&[token_a, token_b], 0));
+
+    let exact_in = amm
+        .quote(&quote_params(&token_a, &token_b, 1_000_000_000, SwapMode::ExactIn))
+        .unwrap();
+    assert_eq!(exact_in.fee_amount, 0);
+    assert_eq!(exact_in.in_amount, 1_000_000_000);
+
+    let exact_out = amm
+        .quote(&quote_params(&token_a, &token_b, exact_in.out_amount, SwapMode::ExactOut))
+        .unwrap();
+    assert_eq!(exact_out.fee_amount, 0);
+    assert_eq!(exact_out.out_amount, exact_in.out_amount);
+    assert!(exact_out.in_amount.abs_diff(exact_in.in_amount) <= 1);
+
+    // Equal weights and balances quote the same in both directions.
+    let reverse = amm
+        .quote(&quote_params(&token_b, &token_a, 1_000_000_000, SwapMode::ExactIn))
+        .unwrap();
+    assert_eq!(reverse.out_amount, exact_in.out_amount);
+}
+
+#[test]
+fn test_pool_descriptor() {
+    let amm = amm(&pool_state(&[record(1_000, PONE / 2), record(1_000, PONE / 2)], 3_000_000));
+
+    assert_eq!(amm.label(), "1DEX");
+    assert_eq!(amm.pool_descriptor(), "1DEX-2tok-0.30%");
+}
+
+#[test]
+fn test_quote_extreme_weight_ratio_matches_reference() {
+    // References computed with 60-digit decimal arithmetic, zero fee.
+    let heavy_in = record(1_000_000_000_000, 98 * PONE / 100);
+    let light_out = record(10_000_000_000_000_000, 2 * PONE / 100);
+    let amm_98_2 = amm(&pool_state(&[heavy_in, light_out], 0));
+    let quote = amm_98_2
+        .quote(&quote_params(&heavy_in, &light_out, 1_000_000, SwapMode::ExactIn))
+        .unwrap();
+    assert!(quote.out_amount.abs_diff(489_987_750_208) <= 1); // 489987750208.2473
+
+    let light_in = record(10_000_000_000_000_000, 2 * PONE / 100);
+    let heavy_out = record(1_000_000_000_000, 98 * PONE / 100);
+    let amm_2_98 = amm(&pool_state(&[light_in, heavy_out], 0));
+    let quote = amm_2_98
+        .quote(&quote_params(&light_in, &heavy_out, 1_000_000, SwapMode::ExactOut))
+        .unwrap();
+    assert!(quote.in_amount.abs_diff(490_012_250_209) <= 1); // 490012250208.2527
+}
+
+#[test]
+fn test_pool_state_round_trip() {
+    let records = [
+        record(1_000_000_000, PONE / 4),
+        record(2_000_000_000, PONE / 4),
+        record(3_000_000_000, PONE / 4),
+        record(4_000_000_000, PONE / 4),
+    ];
+    let state = pool_state(&records, 3_000_000);
+
+    let data = account_data(&state);
+    assert_eq!(data.len(), 8 + POOL_STATE_LEN);
+    assert_eq!(PoolState::deserialize(&mut &data[8..]).unwrap(), state);
+
+    let amm = amm(&state);
+    assert_eq!(*amm.state(), state);
+}
+
+#[test]
+fn test_total_value_in() {
+    let token_a = record(5_000_000_000, PONE / 2);
+    let token_b = record(20_000_000_000, PONE / 2);
+    let amm = amm(&pool_state(&[token_a, token_b], 3_000_000));
+
+    assert_eq!(amm.total_value_in(&token_a.mint_key).unwrap(), 10_000_000_000);
+    assert_eq!(amm.total_value_in(&token_b.mint_key).unwrap(), 40_000_000_000);
+    assert!(amm.total_value_in(&Pubkey::new_unique()).is_err());
+}
+
+#[test]
+fn test_update_requires_only_pool_state() {
+    let token_a = record(5_000_000_000, PONE / 2);
+    let token_b = record(5_000_000_000, PONE / 2);
+    let mut amm = amm(&pool_state(&[token_a, token_b], 3_000_000));
+
+    let mut updated_state = *amm.state();
+    updated_state.pool_token_array[0].balance = 6_000_000_000;
+    let mut account_map: AccountMap = HashMap::new();
+    account_map.insert(amm.key(), keyed_account(&updated_state).account);
+
+    // The pool token accounts are absent from the map.
+    amm.update(&account_map).unwrap();
+    assert_eq!(*amm.state(), updated_state);
+    assert!(amm.stale_accounts().is_empty());
+
+    assert!(amm.update(&HashMap::new()).is_err());
+    assert_eq!(*amm.state(), updated_state);
+}
+
+#[test]
+fn test_update_flags_missing_accounts_as_stale() {
+    let token_a = record(5_000_000_000, PONE / 2);
+    let token_b = record(5_000_000_000, PONE / 2);
+    let state = pool_state(&[token_a, token_b], 3_000_000);
+    let user_token_account = Pubkey::new_unique();
+    let mut amm = amm(&state).with_mint_decimals().with_token_accounts([user_token_account]);
+
+    let mut account_map: AccountMap = HashMap::new();
+    account_map.insert(amm.key(), keyed_account(&state).account);
+    account_map.insert(token_a.mint_key, mint_account(6, spl_token::id()));
+    amm.update(&account_map).unwrap();
+    let mut stale_accounts = amm.stale_accounts().to_vec();
+    stale_accounts.sort();
+    let mut expected = vec![token_b.mint_key, user_token_account];
+    expected.sort();
+    assert_eq!(stale_accounts, expected);
+
+    // Once every account is back nothing is stale
+    account_map.insert(token_b.mint_key, mint_account(9, spl_token::id()));
+    account_map.insert(user_token_account, token_account(token_a.mint_key, Pubkey::new_unique()));
+    amm.update(&account_map).unwrap();
+    assert!(amm.stale_accounts().is_empty());
+}
+
+/// Small xorshift generator so property tests can be replayed from a logged seed.
+struct XorShift(u64);
+
+// Property tests are deterministic by default, `ONE_INTRO_TEST_SEED` explores other seeds.
+const DEFAULT_TEST_SEED: u64 = 0x1DE5_5EED;
+
+impl XorShift {
+    fn from_env() -> (Self, u64) {
+        let seed = std::env::var("ONE_INTRO_TEST_SEED")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or(DEFAULT_TEST_SEED);
+        (XorShift(seed.max(1)), seed)
+    }
+
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn range(&mut self, low: u64, high: u64) -> u64 {
+        low + self.next() % (high - low)
+    }
+}
+
+// f64 math is exact to roughly 1e-10 relative at extreme weights, plus a unit for each floor/ceiling step.
+fn rounding_tolerance(amount: u64) -> u64 {
+    amount / 10_000_000_000 + 3
+}
+
+fn random_pool(rng: &mut XorShift) -> (OneIntroAmm, TokenRecord, TokenRecord) {
+    let weight_in = rng.range(PONE / 50, 49 * PONE / 50);
+    let token_in = record(rng.range(1_000_000, 1_000_000_000_000_000), weight_in);
+    let token_out = record(rng.range(1_000_000, 1_000_000_000_000_000), PONE - weight_in);
+    let fee = rng.range(0, PONE / 100);
+    (amm(&pool_state(&[token_in, token_out], fee)), token_in, token_out)
+}
+
+#[test]
+fn test_property_exact_in_is_monotonic() {
+    let (mut rng, seed) = XorShift::from_env();
+
+    for _ in 0..200 {
+        let (amm, token_in, token_out) = random_pool(&mut rng);
+        let mut amounts = (0..20).map(|_| rng.range(1, token_in.balance / 2)).collect::<Vec<_>>();
+        amounts.sort();
+
+        let mut last_out = 0;
+        for amount in amounts {
+            let Ok(quote) = amm.quote(&quote_params(&token_in, &token_out, amount, SwapMode::ExactIn)) else {
+                continue;
+            };
+            assert!(
+                quote.out_amount >= last_out,
+                "out_amount decreased for in_amount {amount}, replay with ONE_INTRO_TEST_SEED={seed}"
+            );
+            last_out = quote.out_amount;
+        }
+    }
+}
+
+#[test]
+fn test_property_exact_out_inverts_exact_in() {
+    let (mut rng, seed) = XorShift::from_env();
+
+    for _ in 0..200 {
+        let (amm, token_in, token_out) = random_pool(&mut rng);
+        let amount = rng.range(1, token_in.balance / 2);
+        let Ok(exact_in) = amm.quote(&quote_params(&token_in, &token_out, amount, SwapMode::ExactIn)) else {
+            continue;
+        };
+        let exact_out = amm
+            .quote(&quote_params(&token_in, &token_out, exact_in.out_amount, SwapMode::ExactOut))
+            .unwrap();
+
+        // Output is floored, so buying it back never costs more than the original input.
+        assert!(
+            exact_out.in_amount <= amount + rounding_tolerance(amount),
+            "ExactOut needs {} > {amount}, replay with ONE_INTRO_TEST_SEED={seed}",
+            exact_out.in_amount
+        );
+        let round_trip = amm
+            .quote(&quote_params(&token_in, &token_out, exact_out.in_amount, SwapMode::ExactIn))
+            .unwrap();
+        assert!(
+            round_trip.out_amount + rounding_tolerance(exact_in.out_amount) >= exact_in.out_amount,
+            "ExactOut input {} underfills {}, replay with ONE_INTRO_TEST_SEED={seed}",
+            exact_out.in_amount,
+            exact_in.out_amount
+        );
+    }
+}
+
+// A is reproduced within one output unit: buying B never needs more than A, and A never buys B + 1.
+#[test]
+fn test_property_exact_in_then_exact_out_reproduces_amount() {
+    let (mut rng, seed) = XorShift::from_env();
+
+    for _ in 0..200 {
+        let (amm, token_in, token_out) = random_pool(&mut rng);
+        let amount = rng.range(1, token_in.balance / 2);
+        let Ok(exact_in) = amm.quote(&quote_params(&token_in, &token_out, amount, SwapMode::ExactIn)) else {
+            continue;
+        };
+        let required_in_amount = |out_amount: u64| {
+            amm.quote(&quote_params(&token_in, &token_out, out_amount, SwapMode::ExactOut))
+                .map(|v| v.in_amount)
+        };
+
+        let in_amount = required_in_amount(exact_in.out_amount).unwrap();
+        assert!(
+            in_amount <= amount + rounding_tolerance(amount),
+            "ExactOut of {} needs {in_amount} > {amount}, replay with ONE_INTRO_TEST_SEED={seed}",
+            exact_in.out_amount
+        );
+        // One more output unit can be over the liquidity cap, nothing to compare against then.
+        let Ok(next_in_amount) = required_in_amount(exact_in.out_amount + 1) else {
+            continue;
+        };
+        assert!(
+            next_in_amount + rounding_tolerance(amount) > amount,
+            "{amount} also buys {} for {next_in_amount}, replay with ONE_INTRO_TEST_SEED={seed}",
+            exact_in.out_amount + 1
+        );
+    }
+}
+
+#[test]
+fn test_zero_weight_token_is_rejected() {
+    let token_a = record(5_000_000_000, PONE);
+    let delisted = record(5_000_000_000, 0);
+    let amm = amm(&pool_state(&[token_a, delisted], 3_000_000));
+
+    assert_eq!(amm.get_reserve_mints(), vec![token_a.mint_key]);
+    for swap_mode in [SwapMode::ExactIn, SwapMode::ExactOut] {
+        assert!(amm.quote(&quote_params(&token_a, &delisted, 1_000_000, swap_mode)).is_err());
+        assert!(amm.quote(&quote_params(&delisted, &token_a, 1_000_000, swap_mode)).is_err());
+    }
+}
+
+#[test]
+fn test_fee_rate_conversions() {
+    let fee_rate = FeeRate(3_000_000);
+
+    assert_eq!(fee_rate.as_fraction(), Decimal::new(3, 3));
+    assert_eq!(fee_rate.as_bps(), 30);
+    assert_eq!(fee_rate.apply(1_000_000), 3_000);
+    assert_eq!(fee_rate.apply(333), 0);
+
+    let token_in = record(1_000_000_000, PONE / 2);
+    let token_out = record(1_000_000_000, PONE / 2);
+    let amm = amm(&pool_state(&[token_in, token_out], 3_000_000));
+    assert_eq!(amm.fee_rate(), fee_rate);
+    let quote = amm
+        .quote(&quote_params(&token_in, &token_out, 1_000_000, SwapMode::ExactIn))
+        .unwrap();
+    assert_eq!(quote.fee_pct, Decimal::new(3, 3));
+    assert_eq!(quote.fee_amount, 3_000);
+
+    // `fee_pct` used to be a percentage, `0.3` for this pool, it is now the fraction the SPL token-swap AMM reports
+    let percentage_fee_pct = Decimal::new((3_000_000 * 100) as i64, 9);
+    assert_eq!(percentage_fee_pct, Decimal::new(3, 1));
+    assert_eq!(quote.fee_pct * Decimal::ONE_HUNDRED, percentage_fee_pct);
+}
+
+#[test]
+fn test_amount_to_target_price() {
+    let token_in = record(10_000_000_000, 8 * PONE / 10);
+    let token_out = record(20_000_000_000, 2 * PONE / 10);
+    let state = pool_state(&[token_in, token_out], 3_000_000);
+    let amm = amm(&state);
+
+    let spot_price = amm.spot_price(&token_in.mint_key, &token_out.mint_key).unwrap();
+    assert_eq!(spot_price, Decimal::from(8));
+    assert_eq!(amm.amount_to_target_price(&token_in.mint_key, &token_out.mint_key, spot_price).unwrap(), 0);
+    assert_eq!(amm.amount_to_target_price(&token_in.mint_key, &token_out.mint_key, Decimal::from(9)).unwrap(), 0);
+
+    let target_price = Decimal::new(75, 1);
+    let amount = amm.amount_to_target_price(&token_in.mint_key, &token_out.mint_key, target_price).unwrap();
+    let quote = amm
+        .quote(&quote_params(&token_in, &token_out, amount, SwapMode::ExactIn))
+        .unwrap();
+
+    let mut swapped_state = state;
+    swapped_state.pool_token_array[0].balance += quote.in_amount - quote.fee_amount;
+    swapped_state.pool_token_array[1].balance -= quote.out_amount;
+    let swapped_price = OneIntroAmm::from_keyed_account(&keyed_account(&swapped_state))
+        .unwrap()
+        .spot_price(&token_in.mint_key, &token_out.mint_key)
+        .unwrap();
+    assert!((swapped_price - target_price).abs() < Decimal::new(1, 6));
+
+    // Computed with the crate's deterministic pow rather than the platform `powf`
+    let adjusted_in = token_in.balance as f64 * jupiter_core::amms::one_intro::math::pow_minus_one(8.0 / 7.5 - 1.0, 0.2);
+    assert_eq!(amount, (adjusted_in / (1.0 - 0.003)).ceil() as u64);
+
+    let max_in = amm.amount_to_target_price(&token_in.mint_key, &token_out.mint_key, Decimal::new(1, 3)).unwrap();
+    assert_eq!(max_in, token_in.balance / 2);
+}
+
+#[test]
+fn test_pool_quoter_quotes_without_cloning() {
+    let token_in = record(10_000_000_000, PONE / 2);
+    let token_out = record(10_000_000_000, PONE / 2);
+    let amm = amm(&pool_state(&[token_in, token_out], 3_000_000));
+    let expected = amm
+        .quote(&quote_params(&token_in, &token_out, 1_000_000, SwapMode::ExactIn))
+        .unwrap();
+
+    // Each thread borrows the same pool state instead of cloning the AMM.
+    let quoter = amm.quoter();
+    std::thread::scope(|scope| {
+        for _ in 0..4 {
+            scope.spawn(|| {
+                for _ in 0..1_000 {
+                    let quote = quoter
+                        .quote(&quote_params(&token_in, &token_out, 1_000_000, SwapMode::ExactIn))
+                        .unwrap();
+                    assert_eq!(quote.out_amount, expected.out_amount);
+                }
+            });
+        }
+    });
+
+    let quote = PoolQuoter::new(amm.state())
+        .quote(&quote_params(&token_in, &token_out, 1_000_000, SwapMode::ExactIn))
+        .unwrap();
+    assert_eq!(quote.out_amount, expected.out_amount);
+}
+
+#[test]
+fn test_fee_is_reported_on_input_mint() {
+    let token_in = record(10_000_000_000, PONE / 2);
+    let token_out = record(20_000_000_000, PONE / 2);
+    let amm = amm(&pool_state(&[token_in, token_out], 3_000_000));
+
+    for swap_mode in [SwapMode::ExactIn, SwapMode::ExactOut] {
+        let quote = amm
+            .quote(&quote_params(&token_in, &token_out, 1_000_000, swap_mode))
+            .unwrap();
+        assert_eq!(quote.fee_mint, token_in.mint_key);
+
+        // One input token is worth two output tokens at the spot price.
+        let fee_in_output = amm.fee_in_output_token(&quote, &token_out.mint_key).unwrap();
+        assert_eq!(fee_in_output, Decimal::from(quote.fee_amount * 2));
+    }
+}
+
+#[test]
+fn test_swap_metas_reject_non_pool_mints() {
+    let token_a = record(10_000_000_000, PONE / 2);
+    let token_b = record(10_000_000_000, PONE / 2);
+    let unrelated = record(10_000_000_000, PONE / 2);
+    let amm = amm(&pool_state(&[token_a, token_b], 3_000_000));
+
+    let metas = amm.get_swap_and_account_metas(&swap_params(&token_b, &token_a, 1_000, 900)).unwrap();
+    assert_eq!(metas.account_metas[3].pubkey, token_b.account_key);
+    assert_eq!(metas.account_metas[4].pubkey, token_a.account_key);
+
+    assert!(amm.get_swap_and_account_metas(&swap_params(&unrelated, &token_a, 1_000, 900)).is_err());
+    assert!(amm.get_swap_and_account_metas(&swap_params(&token_a, &unrelated, 1_000, 900)).is_err());
+}
+
+#[test]
+fn test_quote_cache_is_cleared_on_update() {
+    let token_in = record(10_000_000_000, PONE / 2);
+    let token_out = record(10_000_000_000, PONE / 2);
+    let state = pool_state(&[token_in, token_out], 3_000_000);
+    let mut amm = amm(&state).with_quote_cache(2);
+    let params = quote_params(&token_in, &token_out, 1_000_000, SwapMode::ExactIn);
+
+    let first = amm.quote(&params).unwrap();
+    assert_eq!(amm.quote_cache_len(), 1);
+    let second = amm.quote(&params).unwrap();
+    assert_eq!(amm.quote_cache_len(), 1);
+    assert_eq!(second.out_amount, first.out_amount);
+
+    let mut updated_state = state;
+    updated_state.pool_token_array[1].balance *= 2;
+    let mut account_map: AccountMap = HashMap::new();
+    account_map.insert(amm.key(), keyed_account(&updated_state).account);
+    amm.update(&account_map).unwrap();
+    assert_eq!(amm.quote_cache_len(), 0);
+
+    let updated = amm.quote(&params).unwrap();
+    assert!(updated.out_amount > first.out_amount);
+
+    // The cache stays bounded.
+    for amount in 1..10 {
+        amm.quote(&quote_params(&token_in, &token_out, amount * 1_000_000, SwapMode::ExactIn)).unwrap();
+        assert!(amm.quote_cache_len() <= 2);
+    }
+}
+
+#[test]
+fn test_price_impact_is_deterministic() {
+    let token_in = record(10_000_000_000, 8 * PONE / 10);
+    let token_out = record(20_000_000_000, 2 * PONE / 10);
+    let amm = amm(&pool_state(&[token_in, token_out], 3_000_000));
+    let price_impact = |amount| {
+        let quote = amm
+            .quote(&quote_params(&token_in, &token_out, amount, SwapMode::ExactIn))
+            .unwrap();
+        amm.price_impact(&token_in.mint_key, &token_out.mint_key, &quote).unwrap()
+    };
+
+    let impact = price_impact(1_000_000_000);
+    assert!(impact > Decimal::ZERO && impact < Decimal::ONE);
+    for _ in 0..10 {
+        assert_eq!(price_impact(1_000_000_000), impact);
+    }
+    assert!(price_impact(2_000_000_000) > impact);
+
+    // Rounding can make a tiny trade look better than spot, which clamps to zero.
+    assert!(price_impact(1_000) >= Decimal::ZERO);
+}
+
+#[test]
+fn test_price_impact_of_an_empty_output_pool_is_an_error() {
+    let token_in = record(10_000_000_000, PONE / 2);
+    let token_out = record(0, PONE / 2);
+    let amm = amm(&pool_state(&[token_in, token_out], 3_000_000));
+
+    // The quote itself succeeds, there is just no output to give
+    let quote = amm.quote(&quote_params(&token_in, &token_out, 1_000_000, SwapMode::ExactIn)).unwrap();
+    assert_eq!(quote.out_amount, 0);
+    assert!(amm.spot_price(&token_in.mint_key, &token_out.mint_key).is_err());
+    assert!(amm.price_impact(&token_in.mint_key, &token_out.mint_key, &quote).is_err());
+}
+
+#[test]
+fn test_total_cost_pct() {
+    let token_in = record(1_000_000_000_000, PONE / 2);
+    let token_out = record(1_000_000_000_000, PONE / 2);
+    let amm = amm(&pool_state(&[token_in, token_out], 3_000_000));
+    let total_cost_pct = |amount| {
+        let quote = amm.quote(&quote_params(&token_in, &token_out, amount, SwapMode::ExactIn)).unwrap();
+        (quote, amm.total_cost_pct(&token_in.mint_key, &token_out.mint_key, &quote).unwrap())
+    };
+
+    // A tiny trade only pays the fee
+    let (quote, cost) = total_cost_pct(1_000_000);
+    let fee_pct = quote.fee_pct * Decimal::ONE_HUNDRED;
+    assert!(cost >= fee_pct);
+    assert!(cost - fee_pct < Decimal::new(1, 3));
+
+    // A large one pays the fee and its price impact on top
+    let (quote, cost) = total_cost_pct(100_000_000_000);
+    let price_impact_pct = amm.price_impact(&token_in.mint_key, &token_out.mint_key, &quote).unwrap() * Decimal::ONE_HUNDRED;
+    assert!(cost > fee_pct + price_impact_pct * Decimal::new(9, 1));
+    assert!(cost < fee_pct + price_impact_pct);
+}
+
+#[test]
+fn test_reserves() {
+    let records = [record(1_000_000_000, PONE / 2), record(2_000_000_000, PONE / 4), record(3_000_000_000, PONE / 4)];
+    let amm = amm(&pool_state(&records, 3_000_000));
+
+    assert_eq!(amm.state().pool_token_array.len(), MAX_TOKEN_COUNT);
+    assert_eq!(amm.reserves(), records.iter().map(|v| (v.mint_key, v.balance)).collect::<Vec<_>>());
+}
+
+#[test]
+fn test_tradeable_pairs() {
+    let records = [
+        record(1_000_000_000, PONE / 4),
+        record(1_000_000_000, PONE / 4),
+        record(1_000_000_000, PONE / 2),
+    ];
+    let amm = amm(&pool_state(&records, 3_000_000));
+
+    let pairs = amm.tradeable_pairs();
+    assert_eq!(pairs.len(), 6);
+    for input in &records {
+        for output in &records {
+            assert_eq!(pairs.contains(&(input.mint_key, output.mint_key)), input != output);
+        }
+    }
+}
+
+#[test]
+fn test_quote_rejects_input_consumed_by_fee() {
+    let token_in = record(10_000_000_000, PONE / 2);
+    let token_out = record(10_000_000_000, PONE / 2);
+    let state = pool_state(&[token_in, token_out], PONE);
+
+    let error = quote_from_state(&state, token_in.mint_key, token_out.mint_key, 1_000, SwapMode::ExactIn).unwrap_err();
+    assert!(error.to_string().contains("too small after the swap fee"));
+
+    let state = pool_state(&[token_in, token_out], 3_000_000);
+    assert!(quote_from_state(&state, token_in.mint_key, token_out.mint_key, 0, SwapMode::ExactIn).is_err());
+}
+
+#[test]
+fn test_get_accounts_to_update_with_context() {
+    let amm = amm(&pool_state(&[record(1_000, PONE / 2), record(1_000, PONE / 2)], 3_000_000));
+
+    assert_eq!(amm.get_accounts_to_update_with_context(AccountUpdateHint::BalancesOnly), vec![amm.key()]);
+    assert_eq!(
+        amm.get_accounts_to_update_with_context(AccountUpdateHint::All),
+        amm.get_accounts_to_update()
+    );
+}
+
+#[test]
+fn test_simulate_swap_does_not_decrease_invariant() {
+    let token_in = record(10_000_000_000, 8 * PONE / 10);
+    let token_out = record(20_000_000_000, 2 * PONE / 10);
+    let amm = amm(&pool_state(&[token_in, token_out], 3_000_000));
+    let invariant = invariant_ln(amm.state());
+
+    for swap_mode in [SwapMode::ExactIn, SwapMode::ExactOut] {
+        for amount in [1_000_000, 100_000_000, 1_000_000_000] {
+            let (quote, swapped_state) = amm
+                .simulate_swap(&quote_params(&token_in, &token_out, amount, swap_mode))
+                .unwrap();
+            assert_eq!(swapped_state.pool_token_array[0].balance, token_in.balance + quote.in_amount);
+            assert_eq!(swapped_state.pool_token_array[1].balance, token_out.balance - quote.out_amount);
+            assert!(invariant_ln(&swapped_state) >= invariant);
+        }
+    }
+
+    // Without a fee the invariant is preserved up to rounding in the pool's favor.
+    let zero_fee = OneIntroAmm::from_keyed_account(&keyed_account(&pool_state(&[token_in, token_out], 0))).unwrap();
+    let (_, swapped_state) = zero_fee
+        .simulate_swap(&quote_params(&token_in, &token_out, 1_000_000_000, SwapMode::ExactIn))
+        .unwrap();
+    let growth = invariant_ln(&swapped_state) - invariant;
+    assert!((0.0..1e-9).contains(&growth));
+}
+
+#[test]
+fn test_error_code_messages() {
+    let cases = [
+        (ErrorCode::CalculationFailure, "Calculation: general failure"),
+        (
+            ErrorCode::ValidationLiquidityTooBigTokenOutAmount,
+            "Transaction Failed: Output token exceeds 50% of the token in pool liquidity. Reduce and retry.",
+        ),
+        (
+            ErrorCode::ValidationTooSmallTokenInAmount,
+            "Validation: Input token amount is too small after the swap fee. Increase and retry.",
+        ),
+        (
+            ErrorCode::ValidationTooSmallTokenOutAmount,
+            "Validation: Output token amount is too small. Increase the input and retry.",
+        ),
+        (ErrorCode::NotEnoughLiquidity, "Validation: Not enough liquidity in the pool for this swap."),
+        (ErrorCode::MintNotInPool, "Validation: Mint is not a token of this pool."),
+    ];
+
+    for (error_code, message) in cases {
+        assert_eq!(error_code.to_string(), message);
+    }
+}
+
+#[test]
+fn test_convert_anchor_error() {
+    let token_in = record(1_000_000_000_000, PONE / 2);
+    let token_out = record(1_000_000, PONE / 2);
+    let amm = amm(&pool_state(&[token_in, token_out], 0));
+
+    let error = amm
+        .quote(&quote_params(&token_in, &token_out, 1, SwapMode::ExactIn))
+        .unwrap_err();
+    assert_eq!(convert_anchor_error(&error), Some(ErrorCode::ValidationTooSmallTokenOutAmount));
+
+    let error = amm.spot_price(&token_in.mint_key, &Pubkey::new_unique()).unwrap_err();
+    assert_eq!(convert_anchor_error(&error), Some(ErrorCode::MintNotInPool));
+
+    assert_eq!(convert_anchor_error(&anyhow::anyhow!("unrelated")), None);
+}
+
+#[test]
+fn test_quote_both_directions_bracket_spot_price() {
+    let token_a = record(10_000_000_000, 8 * PONE / 10);
+    let token_b = record(20_000_000_000, 2 * PONE / 10);
+    let amm = amm(&pool_state(&[token_a, token_b], 3_000_000));
+
+    let (a_to_b, b_to_a) = amm.quote_both_directions(&token_a.mint_key, &token_b.mint_key, 100_000).unwrap();
+    assert_eq!(a_to_b.fee_mint, token_a.mint_key);
+    assert_eq!(b_to_a.fee_mint, token_b.mint_key);
+    assert_eq!(a_to_b.fee_pct, b_to_a.fee_pct);
+
+    let spot_price = amm.spot_price(&token_a.mint_key, &token_b.mint_key).unwrap();
+    let bid = Decimal::from(a_to_b.out_amount) / Decimal::from(a_to_b.in_amount);
+    let ask = Decimal::from(b_to_a.in_amount) / Decimal::from(b_to_a.out_amount);
+    assert!(bid < spot_price && spot_price < ask);
+
+    // A small trade is priced at the spot price less the fee, up to price impact and rounding.
+    let fee_adjusted = Decimal::ONE - a_to_b.fee_pct;
+    assert!((bid - spot_price * fee_adjusted).abs() / spot_price < Decimal::new(1, 4));
+    assert!((ask - spot_price / fee_adjusted).abs() / spot_price < Decimal::new(1, 4));
+}
+
+#[test]
+fn test_update_recomputes_total_weight() {
+    let token_a = record(10_000_000_000, 3 * PONE / 10);
+    let token_b = record(10_000_000_000, 7 * PONE / 10);
+    let state = pool_state(&[token_a, token_b], 3_000_000);
+    let mut amm = amm(&state);
+
+    let mut inconsistent_state = state;
+    inconsistent_state.pool_token_total_weight = 5 * PONE;
+    let mut account_map: AccountMap = HashMap::new();
+    account_map.insert(amm.key(), keyed_account(&inconsistent_state).account);
+    amm.update(&account_map).unwrap();
+    assert_eq!(amm.state().pool_token_total_weight, PONE);
+
+    let mut overflowing_state = state;
+    overflowing_state.pool_token_array[0].weight = u64::MAX;
+    account_map.insert(amm.key(), keyed_account(&overflowing_state).account);
+    assert!(amm.update(&account_map).is_err());
+}
+
+#[test]
+fn test_swap_fixture_builds_consistent_params() {
+    let state = pool_state(&[record(10_000_000_000, PONE / 2), record(10_000_000_000, PONE / 2)], 3_000_000);
+    let amm = amm(&state);
+    let fixture = SwapFixture::new(&state, 1, 0);
+
+    let quote = amm.quote(&fixture.quote_params(1_000_000, SwapMode::ExactIn)).unwrap();
+    let metas = amm
+        .get_swap_and_account_metas(&fixture.swap_params(quote.in_amount, quote.out_amount))
+        .unwrap()
+        .account_metas;
+    assert_eq!(metas[3].pubkey, state.pool_token_array[1].account_key);
+    assert_eq!(metas[4].pubkey, state.pool_token_array[0].account_key);
+    assert_eq!(metas[5].pubkey, fixture.user);
+    assert_eq!(metas[6].pubkey, fixture.source_token_account);
+    assert_eq!(metas[7].pubkey, fixture.destination_token_account);
+
+    let reversed = fixture.reversed();
+    let metas = amm
+        .get_swap_and_account_metas(&reversed.swap_params(quote.in_amount, quote.out_amount))
+        .unwrap()
+        .account_metas;
+    assert_eq!(metas[3].pubkey, state.pool_token_array[0].account_key);
+    assert_eq!(metas[6].pubkey, fixture.destination_token_account);
+}
+
+#[test]
+fn test_single_token_pool_is_rejected() {
+    let token = record(10_000_000_000, PONE);
+    let state = pool_state(&[token], 3_000_000);
+
+    assert!(OneIntroAmm::from_keyed_account(&keyed_account(&state)).is_err());
+    assert!(quote_from_state(&state, token.mint_key, EMPTY_TOKEN_MINT, 1_000, SwapMode::ExactIn).is_err());
+}
+
+#[test]
+fn test_quote_summary() {
+    let token_in = record(10_000_000_000, PONE / 2);
+    let token_out = record(20_000_000_000, PONE / 2);
+    let amm = amm(&pool_state(&[token_in, token_out], 3_000_000));
+    let params = quote_params(&token_in, &token_out, 1_000_000, SwapMode::ExactIn);
+    let quote = amm.quote(&params).unwrap();
+
+    let summary = amm.quote_summary(&params, &quote);
+    assert!(summary.contains(&format!("{} -> {}", token_in.mint_key, token_out.mint_key)));
+    assert!(summary.contains("(0.3%)"));
+    assert!(summary.contains(&format!("out_amount: {}", quote.out_amount)));
+    assert!(summary.contains(&format!("token {}: balance 20000000000", token_out.mint_key)));
+}
+
+#[test]
+fn test_rounding_policy() {
+    let token_in = record(10_000_000_000, 6 * PONE / 10);
+    let token_out = record(20_000_000_000, 4 * PONE / 10);
+    let state = pool_state(&[token_in, token_out], 3_000_000);
+    let exact = amm(&state);
+    let conservative = amm(&state).with_rounding_policy(RoundingPolicy::Conservative { epsilon: 2 });
+    let optimistic = amm(&state).with_rounding_policy(RoundingPolicy::Optimistic { epsilon: 2 });
+
+    for amount in [1_000_000, 10_000_000, 1_000_000_000] {
+        let params = quote_params(&token_in, &token_out, amount, SwapMode::ExactIn);
+        let exact_quote = exact.quote(&params).unwrap();
+        assert_eq!(conservative.quote(&params).unwrap().out_amount, exact_quote.out_amount - 2);
+        assert_eq!(optimistic.quote(&params).unwrap().out_amount, exact_quote.out_amount + 2);
+
+        let params = quote_params(&token_in, &token_out, amount, SwapMode::ExactOut);
+        let exact_quote = exact.quote(&params).unwrap();
+        let conservative_quote = conservative.quote(&params).unwrap();
+        assert_eq!(conservative_quote.in_amount, exact_quote.in_amount + 2);
+        assert_eq!(conservative_quote.out_amount, exact_quote.out_amount);
+    }
+}
+
+#[test]
+fn test_estimate_swap_compute_units() {
+    let estimates = (2..=MAX_TOKEN_COUNT)
+        .map(|token_count| {
+            let records = (0..token_count).map(|_| record(1_000_000_000, PONE)).collect::<Vec<_>>();
+            amm(&pool_state(&records, 3_000_000)).estimate_swap_compute_units(false)
+        })
+        .collect::<Vec<_>>();
+    assert!(estimates.windows(2).all(|v| v[0] < v[1]));
+
+    let amm = amm(&pool_state(&[record(1_000_000_000, PONE), record(1_000_000_000, PONE)], 3_000_000));
+    assert!(amm.estimate_swap_compute_units(true) > amm.estimate_swap_compute_units(false));
+}
+
+#[test]
+fn test_quote_to_all() {
+    let token_a = record(1_000_000_000, PONE / 2);
+    let token_b = record(2_000_000_000, PONE / 4);
+    let token_c = record(3_000_000_000, PONE / 4);
+    let amm = amm(&pool_state(&[token_a, token_b, token_c], 3_000_000));
+
+    let quotes = amm.quote_to_all(&token_b.mint_key, 1_000_000);
+    assert_eq!(quotes.iter().map(|v| v.0).collect::<Vec<_>>(), vec![token_a.mint_key, token_c.mint_key]);
+    for ((_, quote), token_out) in quotes.into_iter().zip([token_a, token_c]) {
+        let expected = amm.quote(&quote_params(&token_b, &token_out, 1_000_000, SwapMode::ExactIn)).unwrap();
+        assert_eq!(format!("{:?}", quote.unwrap()), format!("{:?}", expected));
+    }
+
+    let unknown = Pubkey::new_unique();
+    let mut quotes = amm.quote_to_all(&unknown, 1_000_000);
+    assert_eq!(quotes.len(), 1);
+    let (mint, quote) = quotes.remove(0);
+    assert_eq!(mint, unknown);
+    assert_eq!(convert_anchor_error(&quote.err().unwrap()), Some(ErrorCode::MintNotInPool));
+
+    // Per output errors keep their code too
+    let empty = amm.quote_to_all(&token_b.mint_key, 0);
+    assert!(empty.iter().all(|v| convert_anchor_error(v.1.as_ref().err().unwrap()) == Some(ErrorCode::ValidationTooSmallTokenInAmount)));
+}
+
+#[test]
+fn test_quote_partial_fill() {
+    let token_in = record(1_000_000_000, PONE);
+    let token_out = record(1_000_000_000, PONE);
+    let amm = amm(&pool_state(&[token_in, token_out], 3_000_000));
+
+    let oversized = quote_params(&token_in, &token_out, 900_000_000, SwapMode::ExactIn);
+    assert!(amm.quote(&oversized).unwrap().not_enough_liquidity);
+
+    let quote = amm.quote_partial_fill(&oversized).unwrap();
+    assert_eq!(quote.in_amount, 500_000_000);
+    assert!(!quote.not_enough_liquidity);
+    assert!(quote.out_amount > 0);
+
+    let within_cap = quote_params(&token_in, &token_out, 1_000_000, SwapMode::ExactIn);
+    assert_eq!(amm.quote_partial_fill(&within_cap).unwrap().in_amount, 1_000_000);
+}
+
+#[test]
+fn test_would_partial_fill() {
+    let token_in = record(1_000_000_000, PONE);
+    let token_out = record(1_000_000_000, PONE);
+    let amm = amm(&pool_state(&[token_in, token_out], 3_000_000));
+
+    assert_eq!(amm.max_in_amount(&token_in.mint_key).unwrap(), 500_000_000);
+    assert!(!amm.would_partial_fill(&token_in.mint_key, 499_999_999).unwrap());
+    assert!(!amm.would_partial_fill(&token_in.mint_key, 500_000_000).unwrap());
+    assert!(amm.would_partial_fill(&token_in.mint_key, 500_000_001).unwrap());
+
+    // it agrees with the flag a full quote sets
+    for amount in [500_000_000, 500_000_001] {
+        let quote = amm.quote(&quote_params(&token_in, &token_out, amount, SwapMode::ExactIn)).unwrap();
+        assert_eq!(quote.not_enough_liquidity, amm.would_partial_fill(&token_in.mint_key, amount).unwrap());
+    }
+    assert!(amm.would_partial_fill(&Pubkey::new_unique(), 1).is_err());
+}
+
+#[test]
+fn test_quote_max_in() {
+    let token_in = record(1_000_000_000, 8 * PONE / 10);
+    let token_out = record(3_000_000_000, 2 * PONE / 10);
+    let amm = amm(&pool_state(&[token_in, token_out], 3_000_000));
+
+    let quote = amm.quote_max_in(&token_in.mint_key, &token_out.mint_key, SwapMode::ExactIn).unwrap();
+    assert_eq!(quote.in_amount, amm.max_in_amount(&token_in.mint_key).unwrap());
+    assert_eq!(quote.in_amount, 500_000_000);
+    assert!(!quote.not_enough_liquidity);
+
+    let quote = amm.quote_max_in(&token_in.mint_key, &token_out.mint_key, SwapMode::ExactOut).unwrap();
+    assert_eq!(quote.out_amount, 1_500_000_000);
+    assert!(!quote.not_enough_liquidity);
+}
+
+#[test]
+fn test_typed_balance_and_weight() {
+    // Mixing the units up is a compile error, see the `compile_fail` examples on `Balance` and `Weight`.
+    let token = TokenRecord {
+        weight: 3 * PONE,
+        ..record(1_000_000_001, PONE)
+    };
+    assert_eq!(token.typed_balance(), Balance(1_000_000_001));
+    assert_eq!(token.typed_weight(), Weight(3 * PONE));
+
+    assert_eq!(token.typed_balance().share(PONE / 2).unwrap(), 500_000_000);
+    assert_eq!(token.typed_weight().normalized(Weight(4 * PONE)).unwrap(), 750_000_000);
+    assert!(token.typed_weight().normalized(Weight(0)).is_err());
+    assert_eq!(token.typed_weight().ratio_to(Weight(2 * PONE)), Some(Decimal::new(15, 1)));
+    assert_eq!(token.typed_weight().ratio_to(Weight(0)), None);
+}
+
+#[test]
+fn test_sorted_reserve_mints() {
+    let records = [record(1_000_000_000, PONE), record(2_000_000_000, PONE), record(3_000_000_000, PONE)];
+    let mut reversed = records;
+    reversed.reverse();
+
+    let amm_a = amm(&pool_state(&records, 3_000_000));
+    let amm_b = amm(&pool_state(&reversed, 3_000_000));
+    assert_ne!(amm_a.get_reserve_mints(), amm_b.get_reserve_mints());
+
+    let sorted = amm_a.sorted_reserve_mints();
+    assert_eq!(sorted, amm_b.sorted_reserve_mints());
+    assert!(sorted.windows(2).all(|v| v[0] < v[1]));
+}
+
+#[test]
+fn test_wrapped_sol_steps() {
+    let wsol = TokenRecord {
+        mint_key: spl_token::native_mint::ID,
+        ..record(1_000_000_000, PONE)
+    };
+    let token = record(1_000_000_000, PONE);
+    let amm = amm(&pool_state(&[wsol, token], 3_000_000));
+
+    assert_eq!(
+        amm.wrapped_sol_steps(&swap_params(&wsol, &token, 5_000, 4_000)),
+        vec![
+            WrappedSolStep::CreateTemporaryAccount,
+            WrappedSolStep::Wrap { lamports: 5_000 },
+            WrappedSolStep::CloseTemporaryAccount,
+        ]
+    );
+    assert_eq!(
+        amm.wrapped_sol_steps(&swap_params(&token, &wsol, 5_000, 4_000)),
+        vec![WrappedSolStep::CreateTemporaryAccount, WrappedSolStep::CloseTemporaryAccount]
+    );
+
+    let other = record(1_000_000_000, PONE);
+    assert!(amm.wrapped_sol_steps(&swap_params(&token, &other, 5_000, 4_000)).is_empty());
+}
+
+#[test]
+fn test_quote_out_amount_never_exceeds_out_balance() {
+    let token_in = record(1_000_000, 99 * PONE / 100);
+    let token_out = record(1_000_000_000, PONE / 100);
+    let amm = amm(&pool_state(&[token_in, token_out], 0));
+
+    for amount in [1_000_000, 1_000_000_000, 1_000_000_000_000, u64::MAX / 2] {
+        let quote = amm.quote(&quote_params(&token_in, &token_out, amount, SwapMode::ExactIn)).unwrap();
+        assert!(quote.out_amount <= token_out.balance);
+        assert!(quote.not_enough_liquidity);
+    }
+}
+
+#[cfg(not(feature = "spl-token-swap"))]
+#[test]
+fn test_amm_factory_without_spl_token_swap() {
+    let mut keyed_account = keyed_account(&pool_state(&[record(1_000_000_000, PONE), record(1_000_000_000, PONE)], 0));
+    keyed_account.account.owner = pubkey!("9W959DqEETiGZocYWCQPaJ6sBmUzgfxXfqGeTEdp3aQP"); // Orca v2
+
+    let result = amm_factory(&keyed_account, &mut Default::default());
+    assert!(result.err().unwrap().to_string().starts_with("Unsupported pool"));
+}
+
+#[cfg(feature = "tracing")]
+#[test]
+fn test_failing_quote_emits_tracing_event() {
+    use std::sync::{Arc, Mutex};
+
+    use tracing::{field::{Field, Visit}, span, Event, Metadata, Subscriber};
+
+    /// Records the fields of every event as strings
+    #[derive(Default)]
+    struct EventRecorder(Arc<Mutex<Vec<HashMap<String, String>>>>);
+
+    struct FieldVisitor<'a>(&'a mut HashMap<String, String>);
+
+    impl Visit for FieldVisitor<'_> {
+        fn record_debug(&mut self, field: &Field, value: &dyn std::fmt::Debug) {
+            self.0.insert(field.name().to_string(), format!("{:?}", value));
+        }
+    }
+
+    impl Subscriber for EventRecorder {
+        fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+            true
+        }
+        fn new_span(&self, _span: &span::Attributes<'_>) -> span::Id {
+            span::Id::from_u64(1)
+        }
+        fn record(&self, _span: &span::Id, _values: &span::Record<'_>) {}
+        fn record_follows_from(&self, _span: &span::Id, _follows: &span::Id) {}
+        fn event(&self, event: &Event<'_>) {
+            let mut fields = HashMap::new();
+            event.record(&mut FieldVisitor(&mut fields));
+            self.0.lock().unwrap().push(fields);
+        }
+        fn enter(&self, _span: &span::Id) {}
+        fn exit(&self, _span: &span::Id) {}
+    }
+
+    let token_in = record(1_000_000_000, PONE);
+    let token_out = record(1_000_000_000, PONE);
+    let amm = amm(&pool_state(&[token_in, token_out], 0));
+
+    let recorder = EventRecorder::default();
+    let events = recorder.0.clone();
+    tracing::subscriber::with_default(recorder, || {
+        assert!(amm.quote(&quote_params(&token_in, &token_out, 1, SwapMode::ExactIn)).is_err());
+    });
+
+    let events = events.lock().unwrap();
+    let event = events.iter().find(|v| v.get("message").map(String::as_str) == Some("1DEX quote failed")).unwrap();
+    assert_eq!(event["pool"], amm.key().to_string());
+    assert_eq!(event["input_mint"], token_in.mint_key.to_string());
+    assert_eq!(event["output_mint"], token_out.mint_key.to_string());
+    assert_eq!(event["amount"], "1");
+    assert!(event.contains_key("error"));
+}
+
+#[test]
+fn test_verify_pool_auth_pda() {
+    let pool_key = Pubkey::new_unique();
+    let (pool_auth_pda, bump) =
+        Pubkey::find_program_address(&[POOL_AUTH_PDA_SEED, pool_key.as_ref()], &ONE_INTRO_PROGRAM_ID);
+    let mut state = pool_state(&[record(1_000_000_000, PONE), record(1_000_000_000, PONE)], 3_000_000);
+    state.pool_auth_pda_key = pool_auth_pda;
+    state.pool_auth_pda_bump = bump;
+
+    let mut keyed_account = keyed_account(&state);
+    keyed_account.key = pool_key;
+    OneIntroAmm::from_keyed_account(&keyed_account).unwrap().verify_pool_auth_pda().unwrap();
+
+    state.pool_auth_pda_key = Pubkey::new_unique();
+    let mut keyed_account = self::keyed_account(&state);
+    keyed_account.key = pool_key;
+    let error = OneIntroAmm::from_keyed_account(&keyed_account).unwrap().verify_pool_auth_pda().unwrap_err();
+    assert!(error.to_string().contains("Pool auth PDA mismatch"));
+}
+
+#[test]
+fn test_max_in_for_price_impact() {
+    let token_in = record(1_000_000_000_000, 6 * PONE / 10);
+    let token_out = record(2_000_000_000_000, 4 * PONE / 10);
+    let amm = amm(&pool_state(&[token_in, token_out], 3_000_000));
+
+    for max_impact in [Decimal::new(1, 3), Decimal::new(5, 2)] {
+        let amount = amm.max_in_for_price_impact(&token_in.mint_key, &token_out.mint_key, max_impact).unwrap();
+        let quote = amm.quote(&quote_params(&token_in, &token_out, amount, SwapMode::ExactIn)).unwrap();
+        let impact = amm.price_impact(&token_in.mint_key, &token_out.mint_key, &quote).unwrap();
+        assert!(impact <= max_impact);
+        assert!(impact > max_impact * Decimal::new(99, 2), "impact {} far under {}", impact, max_impact);
+    }
+
+    // A threshold above the impact at the liquidity cap is clamped to the cap
+    let amount = amm.max_in_for_price_impact(&token_in.mint_key, &token_out.mint_key, Decimal::new(9, 1)).unwrap();
+    assert_eq!(amount, token_in.balance / 2);
+}
+
+#[test]
+fn test_max_in_for_price_impact_returns_real_errors() {
+    let token_in = record(1_000_000_000_000, 6 * PONE / 10);
+
+    // Without an output balance there is no spot price, an error rather than a panic or a silent bound
+    let empty_out = record(0, 4 * PONE / 10);
+    let empty = amm(&pool_state(&[token_in, empty_out], 3_000_000));
+    assert!(empty.max_in_for_price_impact(&token_in.mint_key, &empty_out.mint_key, Decimal::new(1, 3)).is_err());
+
+    // An unknown mint is not mistaken for dust either
+    let token_out = record(2_000_000_000_000, 4 * PONE / 10);
+    let pool = amm(&pool_state(&[token_in, token_out], 3_000_000));
+    let error = pool.max_in_for_price_impact(&token_in.mint_key, &Pubkey::new_unique(), Decimal::new(1, 3)).unwrap_err();
+    assert_eq!(convert_anchor_error(&error), Some(ErrorCode::MintNotInPool));
+}
+
+#[test]
+fn test_reserve_mints_are_refreshed_on_update() {
+    let state = pool_state(&[record(1_000_000_000, PONE), record(1_000_000_000, PONE)], 3_000_000);
+    let mut amm = amm(&state);
+    let fresh = |state: &PoolState| {
+        state.active_token_records().filter(|v| v.weight > 0).map(|v| v.mint_key).collect::<Vec<_>>()
+    };
+    assert_eq!(amm.get_reserve_mints(), fresh(&state));
+
+    let mut updated_state = state;
+    updated_state.pool_token_array[1] = record(2_000_000_000, PONE);
+    updated_state.pool_token_array[2] = record(3_000_000_000, PONE);
+    updated_state.pool_token_count = 3;
+    let mut account_map: AccountMap = HashMap::new();
+    account_map.insert(amm.key(), keyed_account(&updated_state).account);
+    amm.update(&account_map).unwrap();
+
+    assert_eq!(amm.get_reserve_mints(), fresh(&updated_state));
+    assert_eq!(amm.get_reserve_mints().len(), 3);
+    assert_eq!(amm.clone().get_reserve_mints(), amm.get_reserve_mints());
+}
+
+#[test]
+fn test_asymmetric_exit_fee() {
+    let token_in = record(10_000_000_000, 6 * PONE / 10);
+    let token_out = record(20_000_000_000, 4 * PONE / 10);
+    let state = pool_state(&[token_in, token_out], 3_000_000);
+    let symmetric = amm(&state);
+    let asymmetric = amm(&state).with_exit_fee_rate(FeeRate(2_000_000));
+
+    let params = quote_params(&token_in, &token_out, 100_000_000, SwapMode::ExactIn);
+    let symmetric_quote = symmetric.quote(&params).unwrap();
+    let asymmetric_quote = asymmetric.quote(&params).unwrap();
+    assert_eq!(asymmetric_quote.fee_amount, symmetric_quote.fee_amount);
+    let expected_out = symmetric_quote.out_amount as f64 * 0.998;
+    assert!((asymmetric_quote.out_amount as f64 - expected_out).abs() <= 1.0);
+
+    let params = quote_params(&token_in, &token_out, 100_000_000, SwapMode::ExactOut);
+    let symmetric_quote = symmetric.quote(&params).unwrap();
+    let asymmetric_quote = asymmetric.quote(&params).unwrap();
+    assert_eq!(asymmetric_quote.out_amount, symmetric_quote.out_amount);
+    assert!(asymmetric_quote.in_amount > symmetric_quote.in_amount);
+
+    // A zero exit fee is the single-fee path
+    let zero_exit_fee = amm(&state).with_exit_fee_rate(FeeRate(0));
+    assert_eq!(zero_exit_fee.quote(&params).unwrap().in_amount, symmetric_quote.in_amount);
+}
+
+#[test]
+fn test_try_from_keyed_account() {
+    let token_in = record(1_000_000_000, PONE);
+    let token_out = record(2_000_000_000, PONE);
+    let keyed_account = keyed_account(&pool_state(&[token_in, token_out], 3_000_000));
+
+    let from_keyed_account = OneIntroAmm::from_keyed_account(&keyed_account).unwrap();
+    let try_from = OneIntroAmm::try_from(&keyed_account).unwrap();
+    assert_eq!(try_from.key(), from_keyed_account.key());
+    assert_eq!(try_from.program_id(), from_keyed_account.program_id());
+    assert_eq!(try_from.state(), from_keyed_account.state());
+
+    let mut invalid = keyed_account;
+    invalid.account.data.truncate(8);
+    assert!(OneIntroAmm::try_from(&invalid).is_err());
+}
+
+#[test]
+fn test_dominant_token() {
+    let light = record(1_000_000_000, PONE / 10);
+    let heavy = record(1_000_000_000, 7 * PONE / 10);
+    let medium = record(1_000_000_000, 2 * PONE / 10);
+    assert_eq!(amm(&pool_state(&[light, heavy, medium], 3_000_000)).dominant_token(), heavy.mint_key);
+
+    let first = record(1_000_000_000, PONE / 2);
+    let second = record(1_000_000_000, PONE / 2);
+    assert_eq!(amm(&pool_state(&[first, second], 3_000_000)).dominant_token(), first.mint_key);
+}
+
+/// (balance_in, weight_in, balance_out, weight_out, swap_fee_ratio, amount, swap_mode, expected).
+/// `expected` is the out amount for ExactIn and the in amount for ExactOut, cross-checked against the
+/// closed-form weighted pool formulas. This is the baseline any change to the pool math must reproduce.
+type GoldenVector = (u64, u64, u64, u64, u64, u64, SwapMode, u64);
+
+const GOLDEN_VECTORS: [GoldenVector; 14] = [
+    (1_000_000_000, PONE / 2, 1_000_000_000, PONE / 2, 0, 1_000_000, SwapMode::ExactIn, 999_000),
+    (1_000_000_000, PONE / 2, 1_000_000_000, PONE / 2, 3_000_000, 1_000_000, SwapMode::ExactIn, 996_006),
+    (1_000_000_000, PONE / 2, 1_000_000_000, PONE / 2, 3_000_000, 100_000_000, SwapMode::ExactIn, 90_661_089),
+    (5_000_000_000, 8 * PONE / 10, 20_000_000_000, 2 * PONE / 10, 3_000_000, 50_000_000, SwapMode::ExactIn, 778_109_423),
+    (20_000_000_000, 2 * PONE / 10, 5_000_000_000, 8 * PONE / 10, 3_000_000, 50_000_000, SwapMode::ExactIn, 3_110_780),
+    (123_456_789_000, 6 * PONE / 10, 987_654_321, 4 * PONE / 10, 10_000_000, 1_000_000_000, SwapMode::ExactIn, 11_762_022),
+    (1_000_000_000_000, PONE / 2, 1_000_000, PONE / 2, 1_000_000, 1_000_000_000, SwapMode::ExactIn, 998),
+    (10_000_000, 98 * PONE / 100, 10_000_000_000, 2 * PONE / 100, 3_000_000, 1_000_000, SwapMode::ExactIn, 9_905_035_131),
+    (1_000_000_000, PONE / 2, 1_000_000_000, PONE / 2, 0, 1_000_000, SwapMode::ExactOut, 1_001_002),
+    (1_000_000_000, PONE / 2, 1_000_000_000, PONE / 2, 3_000_000, 100_000_000, SwapMode::ExactOut, 111_445_448),
+    (5_000_000_000, 8 * PONE / 10, 20_000_000_000, 2 * PONE / 10, 3_000_000, 50_000_000, SwapMode::ExactOut, 3_139_309),
+    (20_000_000_000, 2 * PONE / 10, 5_000_000_000, 8 * PONE / 10, 3_000_000, 50_000_000, SwapMode::ExactOut, 822_875_741),
+    (123_456_789_000, 6 * PONE / 10, 987_654_321, 4 * PONE / 10, 10_000_000, 100_000_000, SwapMode::ExactOut, 9_198_208_646),
+    (10_000_000, 98 * PONE / 100, 10_000_000_000, 2 * PONE / 100, 3_000_000, 1_000_000_000, SwapMode::ExactOut, 21_590),
+];
+
+#[test]
+fn test_quote_golden_vectors() {
+    for (balance_in, weight_in, balance_out, weight_out, swap_fee_ratio, amount, swap_mode, expected) in GOLDEN_VECTORS {
+        let token_in = record(balance_in, weight_in);
+        let token_out = record(balance_out, weight_out);
+        let state = pool_state(&[token_in, token_out], swap_fee_ratio);
+
+        let quote = quote_from_state(&state, token_in.mint_key, token_out.mint_key, amount, swap_mode).unwrap();
+        let actual = match swap_mode {
+            SwapMode::ExactIn => quote.out_amount,
+            SwapMode::ExactOut => quote.in_amount,
+        };
+        assert!(
+            actual.abs_diff(expected) <= 1,
+            "{:?} of {} against ({}, {}) -> ({}, {}) at fee {}: expected {}, got {}",
+            swap_mode, amount, balance_in, weight_in, balance_out, weight_out, swap_fee_ratio, expected, actual,
+        );
+    }
+}
+
+#[test]
+fn test_quote_near_u64_max_balances() {
+    let balance = u64::MAX / 2;
+    assert!(balance > MAX_PRECISE_BALANCE);
+
+    // Equal weights without a fee reduce to the constant product, computed exactly in u128
+    let token_in = record(balance, PONE / 2);
+    let token_out = record(balance, PONE / 2);
+    let pool = amm(&pool_state(&[token_in, token_out], 0));
+    let amount = 1_000_000_000_000_000_000;
+    let quote = pool.quote(&quote_params(&token_in, &token_out, amount, SwapMode::ExactIn)).unwrap();
+    let expected = (balance as u128 * amount as u128 / (balance as u128 + amount as u128)) as u64;
+    assert!((quote.out_amount as f64 - expected as f64).abs() / (expected as f64) < 1e-12);
+
+    // An input that cannot fit a u64 is an error rather than a saturated amount
+    let token_in = record(balance, PONE / 5);
+    let token_out = record(balance, 4 * PONE / 5);
+    let pool = amm(&pool_state(&[token_in, token_out], 0));
+    let error = pool.quote(&quote_params(&token_in, &token_out, balance / 4, SwapMode::ExactOut)).unwrap_err();
+    assert_eq!(convert_anchor_error(&error), Some(ErrorCode::CalculationFailure));
+}
+
+#[test]
+fn test_quote_two_hop() {
+    let token_a = record(10_000_000_000, PONE / 2);
+    let token_b = record(20_000_000_000, PONE / 2);
+    let token_b_other_pool = TokenRecord {
+        mint_key: token_b.mint_key,
+        ..record(5_000_000_000, 4 * PONE / 10)
+    };
+    let token_c = record(8_000_000_000, 6 * PONE / 10);
+    let pool_ab = amm(&pool_state(&[token_a, token_b], 3_000_000));
+    let pool_bc = amm(&pool_state(&[token_b_other_pool, token_c], 10_000_000));
+
+    let amount = 100_000_000;
+    let quote = quote_two_hop(&pool_ab, &pool_bc, token_a.mint_key, token_b.mint_key, token_c.mint_key, amount).unwrap();
+
+    let first = pool_ab.quote(&quote_params(&token_a, &token_b, amount, SwapMode::ExactIn)).unwrap();
+    let second = pool_bc
+        .quote(&quote_params(&token_b_other_pool, &token_c, first.out_amount, SwapMode::ExactIn))
+        .unwrap();
+    assert_eq!(quote.in_amount, amount);
+    assert_eq!(quote.out_amount, second.out_amount);
+    assert_eq!(quote.fee_mint, token_a.mint_key);
+    assert_eq!(quote.fee_pct, Decimal::ONE - Decimal::new(997, 3) * Decimal::new(99, 2));
+    let second_fee_in_a = second.fee_amount as u128 * amount as u128 / first.out_amount as u128;
+    assert_eq!(quote.fee_amount as u128, first.fee_amount as u128 + second_fee_in_a);
+}
+
+#[test]
+fn test_supported_swap_modes() {
+    let token_in = record(1_000_000_000, PONE);
+    let token_out = record(1_000_000_000, PONE);
+    let amm = amm(&pool_state(&[token_in, token_out], 3_000_000)).with_quote_cache(4);
+    assert!(amm.supports_exact_out());
+
+    // The same amount in both modes quotes, and caches, as two distinct swaps
+    let exact_in = amm.quote(&quote_params(&token_in, &token_out, 1_000_000, SwapMode::ExactIn)).unwrap();
+    let exact_out = amm.quote(&quote_params(&token_in, &token_out, 1_000_000, SwapMode::ExactOut)).unwrap();
+    assert_eq!(exact_in.in_amount, 1_000_000);
+    assert_eq!(exact_out.out_amount, 1_000_000);
+    assert!(exact_out.in_amount > exact_in.in_amount);
+    assert_eq!(amm.quote_cache_len(), 2);
+}
+
+#[test]
+fn test_lp_and_token_record_accessors() {
+    let token_a = record(1_000_000_000, PONE / 2);
+    let token_b = record(2_000_000_000, PONE / 2);
+    let state = pool_state(&[token_a, token_b], 3_000_000);
+    let amm = amm(&state);
+
+    assert_eq!(amm.lp_mint(), state.pool_lp_mint_key);
+    assert_eq!(amm.lp_virtual_supply(), state.pool_lp_virtual_supply);
+    assert_eq!(amm.token_records().copied().collect::<Vec<_>>(), vec![token_a, token_b]);
+}
+
+#[test]
+fn test_swap_instruction_with_slippage() {
+    let token_in = record(1_000_000_000, PONE / 2);
+    let token_out = record(1_000_000_000, PONE / 2);
+    let amm = amm(&pool_state(&[token_in, token_out], 3_000_000));
+    let quote = amm.quote(&quote_params(&token_in, &token_out, 1_000_000, SwapMode::ExactIn)).unwrap();
+
+    let instruction = amm
+        .swap_instruction_with_slippage(&swap_params(&token_in, &token_out, quote.in_amount, quote.out_amount), 50)
+        .unwrap();
+    let args = SwapInstructionArgs::deserialize(&mut &instruction.data[8..]).unwrap();
+    assert_eq!(args.token_in_amount, quote.in_amount);
+    assert_eq!(args.minimum_token_out_amount, quote.out_amount * 9_950 / 10_000);
+
+    assert_eq!(minimum_out_amount(1_000, 0).unwrap(), 1_000);
+    assert_eq!(minimum_out_amount(1_000, 10_000).unwrap(), 0);
+    assert!(minimum_out_amount(1_000, 10_001).is_err());
+}
+
+#[test]
+fn test_duplicate_mints_are_rejected() {
+    let token = record(1_000_000_000, PONE / 2);
+    let duplicate = TokenRecord {
+        mint_key: token.mint_key,
+        ..record(2_000_000_000, PONE / 2)
+    };
+    let state = pool_state(&[token, duplicate], 3_000_000);
+
+    let error = OneIntroAmm::from_keyed_account(&keyed_account(&state)).err().unwrap();
+    assert!(error.to_string().contains("listed more than once"));
+
+    let mut amm = amm(&pool_state(&[token, record(1_000_000_000, PONE / 2)], 3_000_000));
+    let mut account_map: AccountMap = HashMap::new();
+    account_map.insert(amm.key(), keyed_account(&state).account);
+    assert!(amm.update(&account_map).is_err());
+}
+
+#[test]
+fn test_quote_with_referrer_fee() {
+    let token_in = record(1_000_000_000, PONE / 2);
+    let token_out = record(1_000_000_000, PONE / 2);
+    let amm = amm(&pool_state(&[token_in, token_out], 3_000_000));
+    let params = quote_params(&token_in, &token_out, 1_000_000, SwapMode::ExactIn);
+    let quote = amm.quote(&params).unwrap();
+
+    let ReferredQuote { quote: split_quote, referrer_fee_amount } = amm.quote_with_referrer_fee(&params, FeeRate(PONE / 2)).unwrap();
+    assert_eq!(split_quote.fee_amount, quote.fee_amount);
+    assert_eq!(split_quote.out_amount, quote.out_amount);
+    assert_eq!(referrer_fee_amount, 1_500);
+
+    let no_referrer = amm.quote_with_referrer_fee(&params, FeeRate(0)).unwrap();
+    assert_eq!(no_referrer.referrer_fee_amount, 0);
+    assert_eq!(no_referrer.pool_fee_amount(), quote.fee_amount);
+
+    // A hand built split above the whole fee leaves the pool nothing rather than wrapping
+    let oversplit = ReferredQuote { quote, referrer_fee_amount: quote.fee_amount + 1 };
+    assert_eq!(oversplit.pool_fee_amount(), 0);
+
+    assert!(amm.quote_with_referrer_fee(&params, FeeRate(PONE + 1)).is_err());
+}
+
+#[test]
+fn test_account_metas_reject_default_user_accounts() {
+    let token_in = record(1_000_000_000, PONE / 2);
+    let token_out = record(1_000_000_000, PONE / 2);
+    let amm = amm(&pool_state(&[token_in, token_out], 3_000_000));
+    assert!(amm.get_swap_and_account_metas(&swap_params(&token_in, &token_out, 1_000, 900)).is_ok());
+
+    let mut params = swap_params(&token_in, &token_out, 1_000, 900);
+    params.source_token_account = Pubkey::default();
+    let error = amm.get_swap_and_account_metas(&params).err().unwrap();
+    assert!(error.to_string().contains("source_token_account"));
+
+    let mut params = swap_params(&token_in, &token_out, 1_000, 900);
+    params.destination_token_account = Pubkey::default();
+    let error = amm.get_swap_and_account_metas(&params).err().unwrap();
+    assert!(error.to_string().contains("destination_token_account"));
+
+    let mut params = swap_params(&token_in, &token_out, 1_000, 900);
+    params.token_transfer_authority = Pubkey::default();
+    let error = amm.get_swap_and_account_metas(&params).err().unwrap();
+    assert!(error.to_string().contains("token_transfer_authority"));
+}
+
+#[test]
+fn test_ideal_out_amount() {
+    let token_in = record(10_000_000_000, 6 * PONE / 10);
+    let token_out = record(20_000_000_000, 4 * PONE / 10);
+    let amm = amm(&pool_state(&[token_in, token_out], 3_000_000));
+
+    for amount in [1_000, 1_000_000, 1_000_000_000] {
+        let quote = amm.quote(&quote_params(&token_in, &token_out, amount, SwapMode::ExactIn)).unwrap();
+        let ideal = amm.ideal_out_amount(&token_in.mint_key, &token_out.mint_key, amount).unwrap();
+        assert!(ideal >= Decimal::from(quote.out_amount));
+    }
+    assert_eq!(amm.ideal_out_amount(&token_in.mint_key, &token_out.mint_key, 1_000).unwrap(), Decimal::from(3_000));
+}
+
+#[test]
+fn test_last_updated() {
+    let state = pool_state(&[record(1_000_000_000, PONE / 2), record(1_000_000_000, PONE / 2)], 3_000_000);
+    let mut amm = amm(&state);
+    assert_eq!(amm.last_updated(), None);
+
+    let mut account_map: AccountMap = HashMap::new();
+    account_map.insert(amm.key(), keyed_account(&state).account);
+    amm.update(&account_map).unwrap();
+    let first = amm.last_updated().unwrap();
+
+    std::thread::sleep(std::time::Duration::from_millis(2));
+    amm.update(&account_map).unwrap();
+    assert!(amm.last_updated().unwrap() > first);
+
+    // A failed update leaves the state, and its timestamp, as they were
+    let last_updated = amm.last_updated();
+    assert!(amm.update(&HashMap::new()).is_err());
+    assert_eq!(amm.last_updated(), last_updated);
+}
+
+#[test]
+fn test_lp_to_underlying() {
+    let token_a = record(1_000_000_000, PONE / 2);
+    let token_b = record(3_000_000_000, PONE / 2);
+    let mut state = pool_state(&[token_a, token_b], 3_000_000);
+    let amm = amm(&state);
+
+    // A quarter of the LP supply is a quarter of every balance
+    let lp_amount = state.pool_lp_virtual_supply / 4;
+    let underlying = amm.lp_to_underlying(lp_amount).unwrap();
+    assert_eq!(underlying, vec![(token_a.mint_key, 250_000_000), (token_b.mint_key, 750_000_000)]);
+    let total = underlying.iter().map(|v| v.1).sum::<u64>();
+    assert_eq!(total, (token_a.balance + token_b.balance) / 4);
+
+    state.pool_lp_virtual_supply = 0;
+    assert!(self::amm(&state).lp_to_underlying(lp_amount).is_err());
+}
+
+#[test]
+fn test_amm_factory_only_loads_pool_states() {
+    assert_eq!(POOL_STATE_DISCRIMINATOR, hash(b"account:PoolState").to_bytes()[..8]);
+
+    let state = pool_state(&[record(1_000_000_000, PONE / 2), record(1_000_000_000, PONE / 2)], 3_000_000);
+    let mut keyed_account = keyed_account(&state);
+    keyed_account.account.data[..8].copy_from_slice(&POOL_STATE_DISCRIMINATOR);
+    assert!(amm_factory(&keyed_account, &mut Default::default()).is_ok());
+
+    // A metadata state shaped account owned by the program
+    let mut metadata_state = keyed_account.clone();
+    metadata_state.account.data = hash(b"account:MetadataState").to_bytes()[..8].to_vec();
+    metadata_state.account.data.extend([0u8; 128]);
+    let result = amm_factory(&metadata_state, &mut Default::default());
+    assert!(result.err().unwrap().to_string().starts_with("Unsupported pool"));
+}
+
+#[test]
+fn test_amm_factory_consults_the_registry() {
+    let owner = Pubkey::new_unique();
+    let mut keyed_account = keyed_account(&pool_state(&[record(1_000_000_000, PONE), record(1_000_000_000, PONE)], 0));
+    keyed_account.account.owner = owner;
+    assert!(amm_factory(&keyed_account, &mut Default::default()).err().unwrap().to_string().starts_with("Unsupported pool"));
+
+    // A dummy AMM reusing the 1DEX pool layout under another program
+    let constructor: AmmConstructor = |keyed_account| {
+        Ok(Box::new(OneIntroAmm::from_keyed_account(keyed_account)?))
+    };
+    let mut registry = AmmRegistry::default();
+    assert!(registry.register(owner, constructor).is_none());
+    assert!(registry.get(&owner).is_some());
+    assert!(registry.get(&ONE_INTRO_PROGRAM_ID).is_none());
+
+    assert!(register_amm(owner, constructor).is_none());
+    let amm = amm_factory(&keyed_account, &mut Default::default()).unwrap();
+    assert_eq!(amm.key(), keyed_account.key);
+    assert_eq!(amm.program_id(), owner);
+}
+
+#[test]
+fn test_weight_ratio() {
+    let token_heavy = record(1_000_000_000, 8 * PONE / 10);
+    let token_light = record(1_000_000_000, 2 * PONE / 10);
+    let amm = amm(&pool_state(&[token_heavy, token_light], 3_000_000));
+
+    assert_eq!(amm.weight_ratio(&token_heavy.mint_key, &token_light.mint_key).unwrap(), Decimal::from(4));
+    assert_eq!(amm.weight_ratio(&token_light.mint_key, &token_heavy.mint_key).unwrap(), Decimal::new(25, 2));
+    assert!(amm.weight_ratio(&token_heavy.mint_key, &Pubkey::new_unique()).is_err());
+}
+
+#[test]
+fn test_exact_out_in_amount_never_underpays() {
+    let pools = [
+        (1_000_000_000, PONE / 2, 1_000_000_000, PONE / 2),
+        (5_000_000_000, 8 * PONE / 10, 20_000_000_000, 2 * PONE / 10),
+        (20_000_000_000, 2 * PONE / 10, 5_000_000_000, 8 * PONE / 10),
+        (123_456_789, 6 * PONE / 10, 987_654_321_000, 4 * PONE / 10),
+    ];
+    for (balance_in, weight_in, balance_out, weight_out) in pools {
+        for swap_fee_ratio in [0, 1_000_000, 3_000_000, 25_000_000] {
+            let token_in = record(balance_in, weight_in);
+            let token_out = record(balance_out, weight_out);
+            let amm = amm(&pool_state(&[token_in, token_out], swap_fee_ratio));
+
+            for amount in [1_000, 12_345, 1_000_000, 77_777_777] {
+                let exact_out = amm.quote(&quote_params(&token_in, &token_out, amount, SwapMode::ExactOut)).unwrap();
+                let exact_in = amm
+                    .quote(&quote_params(&token_in, &token_out, exact_out.in_amount, SwapMode::ExactIn))
+                    .unwrap();
+                assert!(
+                    exact_in.out_amount >= amount,
+                    "{} in for {} out only returns {} at fee {}",
+                    exact_out.in_amount, amount, exact_in.out_amount, swap_fee_ratio,
+                );
+            }
+        }
+    }
+}
+
+#[test]
+fn test_direction_of() {
+    let token_a = record(1_000_000_000, PONE / 2);
+    let token_b = record(1_000_000_000, PONE / 2);
+    let amm = amm(&pool_state(&[token_a, token_b], 3_000_000));
+
+    assert_eq!(amm.direction_of(&token_a.mint_key).unwrap(), SwapDirection::AToB);
+    assert_eq!(amm.direction_of(&token_b.mint_key).unwrap(), SwapDirection::BToA);
+
+    let error = amm.direction_of(&Pubkey::new_unique()).unwrap_err();
+    assert_eq!(convert_anchor_error(&error), Some(ErrorCode::MintNotInPool));
+}
+
+#[test]
+fn test_total_fee_amount_does_not_wrap() {
+    let quote = |fee_amount| Quote {
+        fee_amount,
+        ..Default::default()
+    };
+
+    let small_fees = (0..10_000).map(|_| quote(1_000_000)).collect::<Vec<_>>();
+    assert_eq!(total_fee_amount(&small_fees).unwrap(), 10_000_000_000);
+
+    let max_fees = [quote(u64::MAX / 2), quote(u64::MAX / 2)];
+    assert_eq!(total_fee_amount(&max_fees).unwrap(), u64::MAX - 1);
+
+    let overflowing_fees = (0..1_000).map(|_| quote(u64::MAX)).collect::<Vec<_>>();
+    assert!(total_fee_amount(&overflowing_fees).is_err());
+}
+
+#[test]
+fn test_is_tradeable() {
+    let healthy = pool_state(&[record(1_000_000_000, PONE / 2), record(1_000_000_000, PONE / 2)], 3_000_000);
+    assert!(amm(&healthy).is_tradeable());
+
+    let empty = pool_state(&[record(0, PONE / 2), record(1_000_000_000, PONE / 2)], 3_000_000);
+    assert!(!amm(&empty).is_tradeable());
+
+    let zero_weight = pool_state(
+        &[record(1_000_000_000, PONE / 2), record(1_000_000_000, PONE / 2), record(1_000_000_000, 0)],
+        3_000_000,
+    );
+    assert!(!amm(&zero_weight).is_tradeable());
+}
+
+#[test]
+fn test_math_public_path() {
+    use jupiter_core::amms::one_intro::math::{calc_in_given_out, calc_out_given_in, value_from_shares, FeeRate, PONE};
+
+    // Equal weights without fees: out = bO * aI / (bI + aI)
+    let out_amount = calc_out_given_in(Balance(1_000_000_000), Weight(PONE / 2), Balance(1_000_000_000), Weight(PONE / 2), 1_000_000, FeeRate(0), FeeRate(0)).unwrap();
+    assert_eq!(out_amount, 999_000);
+
+    let in_amount = calc_in_given_out(Balance(1_000_000_000), Weight(PONE / 2), Balance(1_000_000_000), Weight(PONE / 2), out_amount, FeeRate(0)).unwrap();
+    assert!(in_amount.abs_diff(1_000_000) <= 1);
+
+    assert_eq!(value_from_shares(PONE / 2, 1_000_000, PONE).unwrap(), 500_000);
+    assert_eq!(FeeRate(3_000_000).as_bps(), 30);
+}
+
+#[test]
+fn test_quote_is_independent_of_token_array_order() {
+    let token_a = record(3_000_000_000, 7 * PONE / 10);
+    let token_b = record(9_000_000_000, 3 * PONE / 10);
+    let forward = amm(&pool_state(&[token_a, token_b], 3_000_000));
+    let reversed = amm(&pool_state(&[token_b, token_a], 3_000_000));
+
+    for swap_mode in [SwapMode::ExactIn, SwapMode::ExactOut] {
+        for (input, output) in [(&token_a, &token_b), (&token_b, &token_a)] {
+            let params = quote_params(input, output, 10_000_000, swap_mode);
+            let forward_quote = forward.quote(&params).unwrap();
+            let reversed_quote = reversed.quote(&params).unwrap();
+            assert_eq!(forward_quote.in_amount, reversed_quote.in_amount);
+            assert_eq!(forward_quote.out_amount, reversed_quote.out_amount);
+            assert_eq!(forward_quote.fee_amount, reversed_quote.fee_amount);
+        }
+    }
+
+    let error = forward.quote(&quote_params(&token_a, &record(1, 1), 10_000_000, SwapMode::ExactIn)).unwrap_err();
+    assert_eq!(convert_anchor_error(&error), Some(ErrorCode::MintNotInPool));
+    assert!(forward.quote(&quote_params(&token_a, &token_a, 10_000_000, SwapMode::ExactIn)).is_err());
+}
+
+#[test]
+fn test_break_even_in() {
+    let token_in = record(1_000_000_000, 6 * PONE / 10);
+    let token_out = record(3_000_000_000, 4 * PONE / 10);
+
+    for exit_fee_rate in [FeeRate(0), FeeRate(2_000_000)] {
+        let pool = amm(&pool_state(&[token_in, token_out], 3_000_000)).with_exit_fee_rate(exit_fee_rate);
+        let target_price = pool.spot_price(&token_in.mint_key, &token_out.mint_key).unwrap()
+            * (Decimal::ONE - Decimal::new(3, 3))
+            * (Decimal::ONE - exit_fee_rate.as_fraction());
+        // Dust without an output is as bad a price as it gets
+        let effective_price = |amount| match pool.quote(&quote_params(&token_in, &token_out, amount, SwapMode::ExactIn)) {
+            Ok(quote) => Decimal::from(quote.out_amount) / Decimal::from(quote.in_amount),
+            Err(_) => Decimal::ZERO,
+        };
+
+        let break_even = pool.break_even_in(&token_in.mint_key, &token_out.mint_key).unwrap();
+        assert!(effective_price(break_even) >= target_price);
+        for amount in 1..break_even {
+            assert!(effective_price(amount) < target_price, "{amount} breaks even before {break_even}");
+        }
+    }
+
+    let no_fee = amm(&pool_state(&[token_in, token_out], 0));
+    assert!(no_fee.break_even_in(&token_in.mint_key, &token_out.mint_key).is_err());
+
+    // A fee too small to ever be made up for within the searched output amounts is an error, not a best effort
+    let tiny_fee = amm(&pool_state(&[token_in, token_out], 1));
+    assert!(tiny_fee.break_even_in(&token_in.mint_key, &token_out.mint_key).is_err());
+}
+
+fn metadata_account(protocol_swap_fee_ratio: u64) -> Account {
+    let metadata_state = MetadataState {
+        admin_auth_key: Pubkey::new_unique(),
+        protocol_swap_fee_ratio,
+    };
+    let mut data = METADATA_STATE_DISCRIMINATOR.to_vec();
+    metadata_state.serialize(&mut data).unwrap();
+
+    Account {
+        lamports: 1_000_000,
+        data,
+        owner: ONE_INTRO_PROGRAM_ID,
+        executable: false,
+        rent_epoch: 0,
+    }
+}
+
+#[test]
+fn test_metadata_protocol_fee_increases_fee_pct() {
+    let token_in = record(10_000_000_000, PONE / 2);
+    let token_out = record(10_000_000_000, PONE / 2);
+    let state = pool_state(&[token_in, token_out], 3_000_000);
+    let params = quote_params(&token_in, &token_out, 100_000_000, SwapMode::ExactIn);
+
+    let pool_only = amm(&state);
+    assert_eq!(pool_only.get_accounts_to_update(), vec![pool_only.key()]);
+    let pool_only_quote = pool_only.quote(&params).unwrap();
+
+    let mut pool = amm(&state).with_metadata_state();
+    assert_eq!(pool.get_accounts_to_update(), vec![pool.key(), ONE_INTRO_METADATA_STATE]);
+
+    let mut account_map: AccountMap = HashMap::new();
+    account_map.insert(pool.key(), keyed_account(&state).account);
+    account_map.insert(ONE_INTRO_METADATA_STATE, metadata_account(1_000_000));
+    pool.update(&account_map).unwrap();
+
+    assert_eq!(pool.protocol_fee_rate(), Some(FeeRate(1_000_000)));
+    assert_eq!(pool.fee_rate(), FeeRate(4_000_000));
+    let quote = pool.quote(&params).unwrap();
+    assert_eq!(pool_only_quote.fee_pct, Decimal::new(3, 3));
+    assert_eq!(quote.fee_pct, Decimal::new(4, 3));
+    assert!(quote.fee_amount > pool_only_quote.fee_amount);
+    assert!(quote.out_amount < pool_only_quote.out_amount);
+
+    // A missing metadata state keeps the last known protocol fee
+    account_map.remove(&ONE_INTRO_METADATA_STATE);
+    pool.update(&account_map).unwrap();
+    assert_eq!(pool.protocol_fee_rate(), Some(FeeRate(1_000_000)));
+}
+
+#[test]
+fn test_metadata_state_is_validated() {
+    let state = pool_state(&[record(10_000_000_000, PONE / 2), record(10_000_000_000, PONE / 2)], 3_000_000);
+    let update = |metadata_state: Account| {
+        let mut pool = amm(&state).with_metadata_state();
+        let mut account_map: AccountMap = HashMap::new();
+        account_map.insert(pool.key(), keyed_account(&state).account);
+        account_map.insert(ONE_INTRO_METADATA_STATE, metadata_state);
+        pool.update(&account_map).map(|_| pool.fee_rate())
+    };
+
+    assert_eq!(update(metadata_account(PONE - 3_000_001)).unwrap(), FeeRate(PONE - 1));
+    assert!(update(metadata_account(PONE - 3_000_000)).is_err());
+    assert!(update(metadata_account(u64::MAX)).is_err());
+
+    let mut other_account = metadata_account(1_000_000);
+    other_account.data[..8].copy_from_slice(&POOL_STATE_DISCRIMINATOR);
+    assert!(update(other_account).is_err());
+}
+
+#[test]
+fn test_snapshot_restore_round_trip() {
+    let token_in = record(10_000_000_000, 8 * PONE / 10);
+    let token_out = record(20_000_000_000, 2 * PONE / 10);
+    let pool = amm(&pool_state(&[token_in, token_out], 3_000_000));
+
+    let snapshot = pool.snapshot();
+    let restored = OneIntroAmm::restore(pool.key(), pool.program_id(), &snapshot).unwrap();
+    assert_eq!(restored.state(), pool.state());
+    assert_eq!(restored.get_reserve_mints(), pool.get_reserve_mints());
+    for swap_mode in [SwapMode::ExactIn, SwapMode::ExactOut] {
+        let params = quote_params(&token_in, &token_out, 100_000_000, swap_mode);
+        assert_eq!(format!("{:?}", restored.quote(&params).unwrap()), format!("{:?}", pool.quote(&params).unwrap()));
+    }
+
+    assert!(OneIntroAmm::restore(Pubkey::new_unique(), pool.program_id(), &snapshot).is_err());
+    assert!(OneIntroAmm::restore(pool.key(), pool.program_id(), &snapshot[..snapshot.len() - 1]).is_err());
+}
+
+#[test]
+fn test_quote_with_fee_override() {
+    let token_in = record(10_000_000_000, PONE / 2);
+    let token_out = record(10_000_000_000, PONE / 2);
+    let pool = amm(&pool_state(&[token_in, token_out], 3_000_000)).with_quote_cache(8);
+    let params = quote_params(&token_in, &token_out, 100_000_000, SwapMode::ExactIn);
+
+    let at_pool_fee = pool.quote(&params).unwrap();
+    let unchanged = pool.quote_with_fee_override(&params, None).unwrap();
+    assert_eq!(unchanged.out_amount, at_pool_fee.out_amount);
+
+    let at_higher_fee = pool.quote_with_fee_override(&params, Some(FeeRate(10_000_000))).unwrap();
+    assert_eq!(at_higher_fee.fee_pct, Decimal::new(1, 2));
+    assert!(at_higher_fee.fee_amount > at_pool_fee.fee_amount);
+    assert!(at_higher_fee.out_amount < at_pool_fee.out_amount);
+
+    // Neither the state nor the cached quote are affected by the override
+    assert_eq!(pool.fee_rate(), FeeRate(3_000_000));
+    assert_eq!(pool.quote(&params).unwrap().out_amount, at_pool_fee.out_amount);
+}
+
+#[test]
+fn test_f64_to_u64_rounded_rejects_out_of_range_values() {
+    use jupiter_core::amms::one_intro::math::{f64_to_u64_rounded, RoundDirection};
+
+    for rounding in [RoundDirection::Floor, RoundDirection::Ceiling] {
+        for value in [f64::NAN, -1.0, -0.5, f64::INFINITY, f64::NEG_INFINITY, 18_446_744_073_709_551_616.0] {
+            let error = f64_to_u64_rounded(value, rounding).unwrap_err();
+            assert_eq!(convert_anchor_error(&error.into()), Some(ErrorCode::CalculationFailure));
+        }
+    }
+
+    assert_eq!(f64_to_u64_rounded(1.5, RoundDirection::Floor).unwrap(), 1);
+    assert_eq!(f64_to_u64_rounded(1.5, RoundDirection::Ceiling).unwrap(), 2);
+    assert_eq!(f64_to_u64_rounded(-0.0, RoundDirection::Floor).unwrap(), 0);
+}
+
+#[test]
+fn test_metrics_reflect_pool_state() {
+    let token_a = record(1_000_000_000, 5 * PONE / 10);
+    let token_b = record(2_000_000_000, 3 * PONE / 10);
+    let token_c = record(3_000_000_000, 2 * PONE / 10);
+    let pool = amm(&pool_state(&[token_a, token_b, token_c], 3_000_000));
+
+    let metrics = pool.metrics();
+    assert_eq!(
+        metrics,
+        PoolMetrics {
+            token_count: 3,
+            total_weight: PONE,
+            fee_rate: 3_000_000,
+            tokens: [token_a, token_b, token_c]
+                .iter()
+                .map(|v| TokenMetrics {
+                    mint: v.mint_key,
+                    balance: v.balance,
+                    weight: v.weight,
+                })
+                .collect(),
+            lp_virtual_supply: 1_000_000_000,
+        }
+    );
+
+    let json = serde_json::to_value(&metrics).unwrap();
+    assert_eq!(json["token_count"], 3);
+    assert_eq!(json["tokens"][2]["balance"], 3_000_000_000u64);
+}
+
+fn mint_account(decimals: u8, token_program_id: Pubkey) -> Account {
+    use solana_sdk::program_pack::Pack;
+
+    let mint = spl_token::state::Mint {
+        decimals,
+        is_initialized: true,
+        ..Default::default()
+    };
+    let mut data = vec![0u8; spl_token::state::Mint::LEN];
+    mint.pack_into_slice(&mut data);
+
+    Account {
+        lamports: 1_000_000,
+        data,
+        owner: token_program_id,
+        executable: false,
+        rent_epoch: 0,
+    }
+}
+
+#[test]
+fn test_ui_amount_from_loaded_mint_decimals() {
+    let token_in = record(10_000_000_000, PONE / 2);
+    let token_out = record(10_000_000_000, PONE / 2);
+    let state = pool_state(&[token_in, token_out], 3_000_000);
+    let mut pool = amm(&state).with_mint_decimals();
+    assert_eq!(pool.get_accounts_to_update(), vec![pool.key(), token_in.mint_key, token_out.mint_key]);
+    assert!(pool.ui_amount(&token_out.mint_key, 1).is_err());
+
+    let mut account_map: AccountMap = HashMap::new();
+    account_map.insert(pool.key(), keyed_account(&state).account);
+    account_map.insert(token_in.mint_key, mint_account(9, spl_token::id()));
+    account_map.insert(token_out.mint_key, mint_account(6, spl_token::id()));
+    pool.update(&account_map).unwrap();
+
+    assert_eq!(pool.mint_decimals(&token_in.mint_key), Some(9));
+    assert_eq!(pool.mint_decimals(&token_out.mint_key), Some(6));
+    assert_eq!(pool.ui_amount(&token_out.mint_key, 1_500_000).unwrap(), Decimal::new(15, 1));
+    assert_eq!(pool.ui_amount(&token_in.mint_key, 1_500_000).unwrap(), Decimal::new(15, 4));
+
+    // The quote itself stays in raw units
+    let quote = pool.quote(&quote_params(&token_in, &token_out, 100_000_000, SwapMode::ExactIn)).unwrap();
+    assert_eq!(pool.ui_amount(&token_out.mint_key, quote.out_amount).unwrap(), Decimal::new(quote.out_amount as i64, 6));
+}
+
+#[test]
+fn test_mint_decimals_are_best_effort() {
+    let token_in = record(10_000_000_000, PONE / 2);
+    let token_out = record(10_000_000_000, PONE / 2);
+    let state = pool_state(&[token_in, token_out], 3_000_000);
+    let mut pool = amm(&state).with_mint_decimals();
+
+    let mut account_map: AccountMap = HashMap::new();
+    account_map.insert(pool.key(), keyed_account(&state).account);
+    account_map.insert(token_in.mint_key, mint_account(9, spl_token_2022::id()));
+    pool.update(&account_map).unwrap();
+    assert_eq!(pool.mint_decimals(&token_in.mint_key), Some(9));
+    assert_eq!(pool.mint_decimals(&token_out.mint_key), None);
+    assert_eq!(pool.token_program_id(), spl_token_2022::id());
+
+    // A later refresh missing a mint keeps its previous decimals
+    account_map.insert(token_out.mint_key, mint_account(6, spl_token_2022::id()));
+    pool.update(&account_map).unwrap();
+    account_map.remove(&token_in.mint_key);
+    pool.update(&account_map).unwrap();
+    assert_eq!(pool.mint_decimals(&token_in.mint_key), Some(9));
+    assert_eq!(pool.mint_decimals(&token_out.mint_key), Some(6));
+
+    // A new mint applies the state at once and its decimals follow once it is requested
+    let token_new = record(10_000_000_000, PONE / 2);
+    let new_state = pool_state(&[token_in, token_new], 3_000_000);
+    account_map.insert(pool.key(), keyed_account(&new_state).account);
+    pool.update(&account_map).unwrap();
+    assert_eq!(pool.state(), &new_state);
+    assert!(pool.get_accounts_to_update().contains(&token_new.mint_key));
+
+    account_map.insert(token_new.mint_key, mint_account(8, spl_token_2022::id()));
+    pool.update(&account_map).unwrap();
+    assert_eq!(pool.mint_decimals(&token_new.mint_key), Some(8));
+}
+
+#[test]
+fn test_truncated_pool_state_account_is_an_error() {
+    let state = pool_state(&[record(1_000_000_000, PONE / 2), record(1_000_000_000, PONE / 2)], 3_000_000);
+    let mut truncated = keyed_account(&state);
+    truncated.account.data.truncate(4);
+
+    let error = OneIntroAmm::from_keyed_account(&truncated).err().unwrap();
+    assert!(error.to_string().contains("shorter than its discriminator"));
+
+    let mut pool = amm(&state);
+    let mut account_map: AccountMap = HashMap::new();
+    account_map.insert(pool.key(), truncated.account);
+    let error = pool.update(&account_map).unwrap_err();
+    assert!(error.to_string().contains("shorter than its discriminator"));
+    assert_eq!(pool.state(), &state);
+}
+
+#[test]
+fn test_swap_related_accounts_cover_the_swap_metas() {
+    let token_in = record(1_000_000_000, PONE / 2);
+    let token_out = record(1_000_000_000, PONE / 2);
+    let pool = amm(&pool_state(&[token_in, token_out], 3_000_000));
+    let params = swap_params(&token_in, &token_out, 1_000_000, 900_000);
+
+    let accounts = pool.swap_related_accounts(&params);
+    assert_eq!(accounts.len(), accounts.iter().collect::<std::collections::HashSet<_>>().len());
+    assert!(accounts.contains(&pool.state().pool_auth_pda_key));
+    assert!(accounts.contains(&ONE_INTRO_METADATA_STATE));
+
+    let metas = pool.get_swap_and_account_metas(&params).unwrap().account_metas;
+    for meta in metas {
+        assert!(accounts.contains(&meta.pubkey), "{} missing from the swap related accounts", meta.pubkey);
+    }
+}
+
+#[test]
+fn test_bps_fee_unit_quotes_like_pone() {
+    let token_in = record(10_000_000_000, 6 * PONE / 10);
+    let token_out = record(20_000_000_000, 4 * PONE / 10);
+    let pone_pool = amm(&pool_state(&[token_in, token_out], 3_000_000));
+    let bps_pool = amm(&pool_state(&[token_in, token_out], 30)).with_fee_unit(FeeUnit::Bps).unwrap();
+    assert_eq!(bps_pool.fee_rate(), pone_pool.fee_rate());
+
+    for swap_mode in [SwapMode::ExactIn, SwapMode::ExactOut] {
+        let params = quote_params(&token_in, &token_out, 100_000_000, swap_mode);
+        let pone_quote = pone_pool.quote(&params).unwrap();
+        let bps_quote = bps_pool.quote(&params).unwrap();
+        assert_eq!(format!("{:?}", bps_quote), format!("{:?}", pone_quote));
+        assert_eq!(bps_quote.fee_pct, Decimal::new(3, 3));
+    }
+
+    // Read as PONE units, the same 30 is a negligible fee
+    let misread = amm(&pool_state(&[token_in, token_out], 30));
+    assert_eq!(misread.fee_rate(), FeeRate(30));
+}
+
+#[test]
+fn test_bps_fee_of_100_percent_is_rejected() {
+    let records = [record(10_000_000_000, PONE / 2), record(10_000_000_000, PONE / 2)];
+    assert_eq!(FeeUnit::Bps.fee_rate(9_999).unwrap(), FeeRate(PONE - PONE / 10_000));
+    assert!(FeeUnit::Bps.fee_rate(10_000).is_err());
+    assert!(FeeUnit::Bps.fee_rate(u64::MAX).is_err());
+
+    // 10_000 is below PONE as a raw ratio, so the pool state parses, but 100% in basis points
+    let full_fee = pool_state(&records, 10_000);
+    assert!(amm(&full_fee).with_fee_unit(FeeUnit::Bps).is_err());
+    let params = quote_params(&records[0], &records[1], 1_000_000, SwapMode::ExactIn);
+    assert!(PoolQuoter::new(&full_fee).with_fee_unit(FeeUnit::Bps).quote(&params).is_err());
+
+    // An update to a 100% fee is rejected and the last valid state kept
+    let mut pool = amm(&pool_state(&records, 30)).with_fee_unit(FeeUnit::Bps).unwrap();
+    let mut account_map: AccountMap = HashMap::new();
+    account_map.insert(pool.key(), keyed_account(&full_fee).account);
+    assert!(pool.update(&account_map).is_err());
+    assert_eq!(pool.fee_rate(), FeeRate(3_000_000));
+}
+
+#[test]
+fn test_marginal_price_with_fee() {
+    let token_in = record(10_000_000_000, 6 * PONE / 10);
+    let token_out = record(20_000_000_000, 4 * PONE / 10);
+
+    let no_fee = amm(&pool_state(&[token_in, token_out], 0));
+    assert_eq!(
+        no_fee.marginal_price_with_fee(&token_in.mint_key, &token_out.mint_key).unwrap(),
+        no_fee.spot_price(&token_in.mint_key, &token_out.mint_key).unwrap()
+    );
+
+    let with_fee = amm(&pool_state(&[token_in, token_out], 3_000_000));
+    let spot_price = with_fee.spot_price(&token_in.mint_key, &token_out.mint_key).unwrap();
+    let marginal_price = with_fee.marginal_price_with_fee(&token_in.mint_key, &token_out.mint_key).unwrap();
+    assert!(marginal_price > spot_price);
+    assert_eq!(marginal_price, spot_price / Decimal::new(997, 3));
+}
+
+#[test]
+fn test_calc_in_given_out_pow_overflow_is_a_calculation_failure() {
+    use jupiter_core::amms::one_intro::math::calc_in_given_out;
+
+    // diff = bO - aO = 1, so bO / diff = 1e18 is raised to the weight ratio 49, far beyond f64::MAX
+    let balance = 1_000_000_000_000_000_000;
+    let error = calc_in_given_out(Balance(balance), Weight(PONE / 50), Balance(balance), Weight(49 * PONE / 50), balance - 1, FeeRate(0)).unwrap_err();
+    assert_eq!(convert_anchor_error(&error.into()), Some(ErrorCode::CalculationFailure));
+}
+
+#[test]
+fn test_max_price_impact_rejects_quotes_over_the_ceiling() {
+    let token_in = record(10_000_000_000, PONE / 2);
+    let token_out = record(10_000_000_000, PONE / 2);
+    let state = pool_state(&[token_in, token_out], 3_000_000);
+    let under = quote_params(&token_in, &token_out, 1_000_000_000, SwapMode::ExactIn);
+    let over = quote_params(&token_in, &token_out, 1_010_000_000, SwapMode::ExactIn);
+
+    let unbounded = amm(&state);
+    let under_quote = unbounded.quote(&under).unwrap();
+    let ceiling = unbounded.price_impact(&token_in.mint_key, &token_out.mint_key, &under_quote).unwrap();
+    assert!(unbounded.quote(&over).is_ok());
+
+    let strict = amm(&state).with_max_price_impact(ceiling);
+    assert_eq!(strict.quote(&under).unwrap().out_amount, under_quote.out_amount);
+    let error = strict.quote(&over).unwrap_err();
+    assert!(error.to_string().contains("exceeds the maximum"));
+}
+
+#[test]
+fn test_in_for_out_fraction() {
+    let token_in = record(10_000_000_000, PONE / 2);
+    let token_out = record(20_000_000_000, PONE / 2);
+    let pool = amm(&pool_state(&[token_in, token_out], 3_000_000));
+
+    let in_amount = pool.in_for_out_fraction(&token_in.mint_key, &token_out.mint_key, Decimal::new(1, 1)).unwrap();
+    let exact_out = pool
+        .quote(&quote_params(&token_in, &token_out, 2_000_000_000, SwapMode::ExactOut))
+        .unwrap();
+    assert_eq!(in_amount, exact_out.in_amount);
+
+    let error = pool.in_for_out_fraction(&token_in.mint_key, &token_out.mint_key, Decimal::new(6, 1)).unwrap_err();
+    assert_eq!(convert_anchor_error(&error), Some(ErrorCode::ValidationLiquidityTooBigTokenOutAmount));
+    assert!(pool.in_for_out_fraction(&token_in.mint_key, &token_out.mint_key, Decimal::ZERO).is_err());
+}
+
+#[test]
+fn test_fee_components_sum_to_fee_amount() {
+    let token_in = record(10_000_000_000, PONE / 2);
+    let token_out = record(10_000_000_000, PONE / 2);
+    let state = pool_state(&[token_in, token_out], 3_000_000);
+    let mut pool = amm(&state).with_metadata_state();
+    let mut account_map: AccountMap = HashMap::new();
+    account_map.insert(pool.key(), keyed_account(&state).account);
+    account_map.insert(ONE_INTRO_METADATA_STATE, metadata_account(1_000_000));
+    pool.update(&account_map).unwrap();
+
+    for swap_mode in [SwapMode::ExactIn, SwapMode::ExactOut] {
+        let params = quote_params(&token_in, &token_out, 123_456_789, swap_mode);
+        let components: QuoteFeeComponents = pool.quote_with_fee_components(&params).unwrap();
+
+        assert_eq!(components.swap_fee_amount() + components.protocol_fee_amount, components.quote.fee_amount);
+        // 0.1% of the 0.4% total is the protocol's quarter
+        assert_eq!(components.protocol_fee_amount, components.quote.fee_amount / 4);
+    }
+
+    let without_metadata = amm(&state);
+    let params = quote_params(&token_in, &token_out, 123_456_789, SwapMode::ExactIn);
+    let components = without_metadata.quote_with_fee_components(&params).unwrap();
+    assert_eq!(components.protocol_fee_amount, 0);
+    assert_eq!(components.swap_fee_amount(), components.quote.fee_amount);
+
+    // A hand built split above the whole fee leaves no swap fee rather than wrapping
+    let oversplit = QuoteFeeComponents { quote: components.quote, protocol_fee_amount: components.quote.fee_amount + 1 };
+    assert_eq!(oversplit.swap_fee_amount(), 0);
+}
+
+#[test]
+fn test_reserve_mints_exclude_the_system_sentinel() {
+    let token_a = record(1_000_000_000, PONE / 2);
+    let token_b = record(1_000_000_000, PONE / 2);
+    let state = pool_state(&[token_a, token_b], 3_000_000);
+    assert_eq!(state.pool_token_array[2].mint_key, EMPTY_TOKEN_MINT);
+    assert_eq!(state.pool_token_array[3].mint_key, EMPTY_TOKEN_MINT);
+    assert_eq!(EMPTY_TOKEN_MINT, pubkey!("11111111111111111111111111111111"));
+
+    let pool = amm(&state);
+    assert_eq!(pool.get_reserve_mints(), vec![token_a.mint_key, token_b.mint_key]);
+}
+
+#[test]
+fn test_is_equal_weight() {
+    let equal = pool_state(&[record(1_000_000_000, PONE / 2), record(3_000_000_000, PONE / 2)], 3_000_000);
+    assert!(amm(&equal).is_equal_weight());
+
+    let weighted = pool_state(&[record(1_000_000_000, 8 * PONE / 10), record(3_000_000_000, 2 * PONE / 10)], 3_000_000);
+    assert!(!amm(&weighted).is_equal_weight());
+}
+
+#[test]
+fn test_equal_weight_pairs_quote_with_constant_product() {
+    use jupiter_core::amms::one_intro::math::{calc_out_given_in, calc_out_given_in_constant_product};
+
+    let (balance_in, balance_out, amount) = (7_000_000_000, 13_000_000_000, 123_456_789);
+    let fee = FeeRate(3_000_000);
+    let adjusted_amount = amount - fee.apply(amount);
+
+    let token_in = record(balance_in, PONE / 2);
+    let token_out = record(balance_out, PONE / 2);
+    let equal = amm(&pool_state(&[token_in, token_out], fee.0));
+    let quote = equal.quote(&quote_params(&token_in, &token_out, amount, SwapMode::ExactIn)).unwrap();
+
+    let constant_product = balance_out as u128 * adjusted_amount as u128 / (balance_in + adjusted_amount) as u128;
+    assert_eq!(calc_out_given_in_constant_product(Balance(balance_in), Balance(balance_out), adjusted_amount, FeeRate(0)).unwrap() as u128, constant_product);
+    let general = calc_out_given_in(Balance(balance_in), Weight(PONE / 2), Balance(balance_out), Weight(PONE / 2), adjusted_amount, FeeRate(0), FeeRate(0)).unwrap();
+    assert!(general.abs_diff(constant_product as u64) <= 1);
+    assert_eq!(quote.out_amount as u128, constant_product.min(general as u128));
+
+    // Equal weights match the general path within rounding and never exceed the program's f64 result, for a 50/50
+    // pool and for an equal weight pair of a larger pool alike
+    let (mut rng, seed) = XorShift::from_env();
+    for _ in 0..200 {
+        let balance_in = rng.range(1_000_000, 1_000_000_000_000);
+        let balance_out = rng.range(1_000_000, 1_000_000_000_000);
+        let amount = rng.range(1_000, balance_in / 2);
+        let adjusted_amount = amount - fee.apply(amount);
+        let Ok(general) = calc_out_given_in(Balance(balance_in), Weight(PONE / 2), Balance(balance_out), Weight(PONE / 2), adjusted_amount, FeeRate(0), FeeRate(0)) else {
+            continue;
+        };
+        let token_in = record(balance_in, PONE / 4);
+        let token_out = record(balance_out, PONE / 4);
+        for records in [vec![token_in, token_out], vec![token_in, token_out, record(5_000_000_000, PONE / 2)]] {
+            let pool = amm(&pool_state(&records, fee.0));
+            if let Ok(quote) = pool.quote(&quote_params(&token_in, &token_out, amount, SwapMode::ExactIn)) {
+                assert!(quote.out_amount <= general, "seed {seed}: {quote:?} above {general}");
+                assert!(general - quote.out_amount <= 1, "seed {seed}: {quote:?} far under {general}");
+            }
+        }
+    }
+
+    // A skewed pool is not constant product, the input token weighing more buys more output
+    let token_in = record(balance_in, 8 * PONE / 10);
+    let token_out = record(balance_out, 2 * PONE / 10);
+    let skewed = amm(&pool_state(&[token_in, token_out], fee.0));
+    let quote = skewed.quote(&quote_params(&token_in, &token_out, amount, SwapMode::ExactIn)).unwrap();
+    assert!(quote.out_amount as u128 > constant_product);
+    let general = calc_out_given_in(Balance(balance_in), Weight(8 * PONE / 10), Balance(balance_out), Weight(2 * PONE / 10), adjusted_amount, FeeRate(0), FeeRate(0)).unwrap();
+    assert_eq!(quote.out_amount, general);
+}
+
+#[test]
+fn test_fee_ratio_of_pone_or_more_is_rejected() {
+    let records = [record(1_000_000_000, PONE / 2), record(1_000_000_000, PONE / 2)];
+    let valid = pool_state(&records, PONE - 1);
+    let mut pool = amm(&valid);
+
+    for fee in [PONE, PONE + 1] {
+        let state = pool_state(&records, fee);
+        assert!(OneIntroAmm::from_keyed_account(&keyed_account(&state)).is_err());
+
+        let mut account_map: AccountMap = HashMap::new();
+        account_map.insert(pool.key(), keyed_account(&state).account);
+        assert!(pool.update(&account_map).is_err());
+        assert_eq!(pool.state(), &valid);
+    }
+}
+
+#[test]
+fn test_deterministic_pow_matches_powf() {
+    use jupiter_core::amms::one_intro::math::{exp, exp_m1, ln, ln_1p, pow, pow_minus_one};
+
+    let relative_error = |actual: f64, expected: f64| ((actual - expected) / expected).abs();
+    let (mut rng, seed) = XorShift::from_env();
+
+    for _ in 0..10_000 {
+        // Bases around the pool balance ratios, exponents across the weight ratios of 1/49 to 49
+        let base = rng.range(1, 1 << 53) as f64 / rng.range(1, 1 << 53) as f64;
+        let n = rng.range(20_000, 49_000_000) as f64 / 1_000_000.0;
+        let tolerance = 1e-15 * (1.0 + (n * base.ln()).abs());
+
+        let expected = base.powf(n);
+        if expected.is_normal() {
+            assert!(
+                relative_error(pow(base, n), expected) <= tolerance,
+                "pow({base}, {n}) = {} against powf {expected}, replay with ONE_INTRO_TEST_SEED={seed}",
+                pow(base, n)
+            );
+        }
+        let x = base - 1.0;
+        let expected = (n * x.ln_1p()).exp_m1();
+        if expected.is_normal() {
+            assert!(
+                relative_error(pow_minus_one(x, n), expected) <= tolerance,
+                "pow_minus_one({x}, {n}) = {} against {expected}, replay with ONE_INTRO_TEST_SEED={seed}",
+                pow_minus_one(x, n)
+            );
+        }
+
+        assert!(relative_error(ln(base), base.ln()) <= 1e-15 || (ln(base) - base.ln()).abs() <= 1e-16);
+        assert!(relative_error(ln_1p(x), x.ln_1p()) <= 1e-15);
+        let y = (rng.range(0, 1_400_000) as f64 - 700_000.0) / 1_000.0;
+        assert!(relative_error(exp(y), y.exp()) <= 1e-15, "exp({y}), replay with ONE_INTRO_TEST_SEED={seed}");
+        assert!(relative_error(exp_m1(y / 700.0), (y / 700.0).exp_m1()) <= 1e-15);
+    }
+
+    assert_eq!(pow(0.0, 2.5), 0.0);
+    assert_eq!(pow(1.0, 49.0), 1.0);
+    assert_eq!(pow(2.0, 10.0), 1024.0);
+    assert_eq!(exp(0.0), 1.0);
+    assert_eq!(ln(1.0), 0.0);
+    assert!(exp(710.0).is_infinite());
+    assert!(ln(-1.0).is_nan());
+}
+
+#[test]
+fn test_quote_raw_matches_pool_state_quotes() {
+    let token_in = record(5_000_000_000, 8 * PONE / 10);
+    let token_out = record(20_000_000_000, 2 * PONE / 10);
+    let state = pool_state(&[token_in, token_out], 3_000_000);
+
+    for swap_mode in [SwapMode::ExactIn, SwapMode::ExactOut] {
+        let quote = quote_from_state(&state, token_in.mint_key, token_out.mint_key, 50_000_000, swap_mode).unwrap();
+        let raw = quote_raw(5_000_000_000, 8 * PONE / 10, 20_000_000_000, 2 * PONE / 10, 3_000_000, 50_000_000, swap_mode)
+            .unwrap();
+        assert_eq!(
+            raw,
+            RawQuote {
+                in_amount: quote.in_amount,
+                out_amount: quote.out_amount,
+                fee_amount: quote.fee_amount,
+                not_enough_liquidity: quote.not_enough_liquidity,
+            }
+        );
+    }
+
+    let raw = quote_raw(1_000_000_000, PONE / 2, 1_000_000_000, PONE / 2, 0, 600_000_000, SwapMode::ExactOut).unwrap();
+    assert!(raw.not_enough_liquidity);
+    assert!(quote_raw(1_000_000_000, 0, 1_000_000_000, PONE / 2, 0, 1_000, SwapMode::ExactIn).is_err());
+}
+
+#[test]
+fn test_swap_metas_reject_an_empty_output_balance() {
+    let token_in = record(1_000_000_000, PONE / 2);
+    let token_out = record(0, PONE / 2);
+    let pool = amm(&pool_state(&[token_in, token_out], 3_000_000));
+
+    let error = pool.get_swap_and_account_metas(&swap_params(&token_in, &token_out, 1_000_000, 0)).err().unwrap();
+    assert_eq!(convert_anchor_error(&error), Some(ErrorCode::NotEnoughLiquidity));
+    // The other direction still has an output balance to swap out
+    assert!(pool.get_swap_and_account_metas(&swap_params(&token_out, &token_in, 1_000_000, 0)).is_ok());
+}
+
+#[test]
+fn test_swap_metas_use_the_pool_token_program() {
+    let token_in = record(1_000_000_000, PONE / 2);
+    let token_out = record(1_000_000_000, PONE / 2);
+    let state = pool_state(&[token_in, token_out], 3_000_000);
+    let params = swap_params(&token_in, &token_out, 1_000_000, 0);
+    let token_program = |pool: &OneIntroAmm| {
+        let metas = pool.get_swap_and_account_metas(&params).ok().unwrap().account_metas;
+        metas.last().unwrap().pubkey
+    };
+
+    assert_eq!(token_program(&amm(&state)), spl_token::id());
+    let configured = amm(&state).with_token_program_id(spl_token_2022::id());
+    assert_eq!(token_program(&configured), spl_token_2022::id());
+    assert!(configured.swap_related_accounts(&params).contains(&spl_token_2022::id()));
+
+    for token_program_id in [spl_token::id(), spl_token_2022::id()] {
+        let mut pool = amm(&state).with_mint_decimals();
+        let mut account_map: AccountMap = HashMap::new();
+        account_map.insert(pool.key(), keyed_account(&state).account);
+        account_map.insert(token_in.mint_key, mint_account(9, token_program_id));
+        account_map.insert(token_out.mint_key, mint_account(6, token_program_id));
+        pool.update(&account_map).unwrap();
+
+        assert_eq!(pool.token_program_id(), token_program_id);
+        assert_eq!(token_program(&pool), token_program_id);
+    }
+
+    let mut mixed = amm(&state).with_mint_decimals();
+    let mut account_map: AccountMap = HashMap::new();
+    account_map.insert(mixed.key(), keyed_account(&state).account);
+    account_map.insert(token_in.mint_key, mint_account(9, spl_token::id()));
+    account_map.insert(token_out.mint_key, mint_account(6, spl_token_2022::id()));
+    assert!(mixed.update(&account_map).is_err());
+}
+
+#[test]
+fn test_identical_update_keeps_the_quote_cache() {
+    let token_in = record(10_000_000_000, PONE / 2);
+    let token_out = record(10_000_000_000, PONE / 2);
+    let state = pool_state(&[token_in, token_out], 3_000_000);
+    let mut pool = amm(&state).with_quote_cache(4);
+    let params = quote_params(&token_in, &token_out, 1_000_000, SwapMode::ExactIn);
+    let mut account_map: AccountMap = HashMap::new();
+    account_map.insert(pool.key(), keyed_account(&state).account);
+
+    pool.quote(&params).unwrap();
+    for _ in 0..2 {
+        pool.update(&account_map).unwrap();
+        assert_eq!(pool.quote_cache_len(), 1);
+        assert!(pool.last_updated().is_some());
+    }
+
+    let mut updated_state = state;
+    updated_state.pool_swap_fee_ratio = 1_000_000;
+    account_map.insert(pool.key(), keyed_account(&updated_state).account);
+    pool.update(&account_map).unwrap();
+    assert_eq!(pool.quote_cache_len(), 0);
+    assert_eq!(pool.fee_rate(), FeeRate(1_000_000));
+
+    // A changed protocol fee invalidates the cache even if the pool state is identical
+    let mut pool = amm(&state).with_metadata_state().with_quote_cache(4);
+    account_map.insert(pool.key(), keyed_account(&state).account);
+    account_map.insert(ONE_INTRO_METADATA_STATE, metadata_account(0));
+    pool.update(&account_map).unwrap();
+    pool.quote(&params).unwrap();
+    account_map.insert(ONE_INTRO_METADATA_STATE, metadata_account(1_000_000));
+    pool.update(&account_map).unwrap();
+    assert_eq!(pool.quote_cache_len(), 0);
+}
+
+fn token_account(mint: Pubkey, owner: Pubkey) -> Account {
+    use solana_sdk::program_pack::Pack;
+
+    let token_account = spl_token::state::Account {
+        mint,
+        owner,
+        amount: 1_000_000,
+        state: spl_token::state::AccountState::Initialized,
+        ..Default::default()
+    };
+    let mut data = vec![0u8; spl_token::state::Account::LEN];
+    token_account.pack_into_slice(&mut data);
+
+    Account {
+        lamports: 1_000_000,
+        data,
+        owner: spl_token::id(),
+        executable: false,
+        rent_epoch: 0,
+    }
+}
+
+#[test]
+fn test_swap_metas_reject_a_source_token_account_of_another_mint() {
+    let token_in = record(1_000_000_000, PONE / 2);
+    let token_out = record(1_000_000_000, PONE / 2);
+    let state = pool_state(&[token_in, token_out], 3_000_000);
+    let params = swap_params(&token_in, &token_out, 1_000_000, 0);
+
+    // Without the account data nothing is checked
+    let mut pool = amm(&state).with_token_accounts([params.source_token_account]);
+    assert!(pool.get_accounts_to_update().contains(&params.source_token_account));
+    assert!(pool.get_swap_and_account_metas(&params).is_ok());
+
+    let mut account_map: AccountMap = HashMap::new();
+    account_map.insert(pool.key(), keyed_account(&state).account);
+    pool.update(&account_map).unwrap();
+    assert!(pool.get_swap_and_account_metas(&params).is_ok());
+
+    account_map.insert(params.source_token_account, token_account(token_in.mint_key, params.token_transfer_authority));
+    pool.update(&account_map).unwrap();
+    assert!(pool.get_swap_and_account_metas(&params).is_ok());
+
+    account_map.insert(params.source_token_account, token_account(token_out.mint_key, params.token_transfer_authority));
+    pool.update(&account_map).unwrap();
+    let error = pool.get_swap_and_account_metas(&params).err().unwrap();
+    assert!(error.to_string().contains("not the source mint"));
+
+    account_map.insert(params.source_token_account, mint_account(9, spl_token::id()));
+    assert!(pool.update(&account_map).is_err());
+}
+
+#[test]
+fn test_swap_metas_user_signer_flag() {
+    let token_in = record(1_000_000_000, PONE / 2);
+    let token_out = record(1_000_000_000, PONE / 2);
+    let amm = amm(&pool_state(&[token_in, token_out], 3_000_000));
+    let params = swap_params(&token_in, &token_out, 1_000_000, 0);
+    let user_meta = |authority| {
+        let metas = amm.get_swap_and_account_metas_with_authority(&params, authority).ok().unwrap().account_metas;
+        metas.into_iter().find(|v| v.pubkey == params.token_transfer_authority).unwrap()
+    };
+
+    let signer = user_meta(TransferAuthority::Signer);
+    assert!(signer.is_signer && signer.is_writable);
+    let default_metas = amm.get_swap_and_account_metas(&params).ok().unwrap().account_metas;
+    assert!(default_metas.contains(&signer));
+
+    let delegate = user_meta(TransferAuthority::Delegate);
+    assert!(!delegate.is_signer && delegate.is_writable);
+}
+
+#[test]
+fn test_over_cap_quotes_are_flagged_not_errors() {
+    let token_in = record(1_000_000_000, PONE / 5);
+    let token_out = record(1_000_000_000, 4 * PONE / 5);
+    let amm = amm(&pool_state(&[token_in, token_out], 3_000_000));
+    let quote = |amount, swap_mode| amm.quote(&quote_params(&token_in, &token_out, amount, swap_mode));
+
+    for amount in [500_000_001, 1_000_000_000, 10_000_000_000] {
+        let exact_in = quote(amount, SwapMode::ExactIn).unwrap();
+        assert!(exact_in.not_enough_liquidity);
+        assert_eq!(exact_in.in_amount, amount);
+    }
+
+    for amount in [500_000_001, 600_000_000] {
+        let exact_out = quote(amount, SwapMode::ExactOut).unwrap();
+        assert!(exact_out.not_enough_liquidity);
+        assert_eq!(exact_out.out_amount, amount);
+    }
+
+    // No input buys the whole balance or more, those are quoted at the cap
+    let at_cap = quote(500_000_000, SwapMode::ExactOut).unwrap();
+    assert!(!at_cap.not_enough_liquidity);
+    for amount in [1_000_000_000, 1_500_000_000] {
+        let exact_out = quote(amount, SwapMode::ExactOut).unwrap();
+        assert!(exact_out.not_enough_liquidity);
+        assert_eq!(exact_out.out_amount, 500_000_000);
+        assert_eq!(exact_out.in_amount, at_cap.in_amount);
+        assert_eq!(exact_out.fee_amount, at_cap.fee_amount);
+    }
+
+    // A price impact limit does not turn the flag back into an error, while in-cap quotes stay bound by it
+    let limited = self::amm(&pool_state(&[token_in, token_out], 3_000_000)).with_max_price_impact(Decimal::new(1, 2));
+    for swap_mode in [SwapMode::ExactIn, SwapMode::ExactOut] {
+        let quote = limited.quote(&quote_params(&token_in, &token_out, 1_000_000_000, swap_mode)).unwrap();
+        assert!(quote.not_enough_liquidity);
+        assert!(limited.quote(&quote_params(&token_in, &token_out, 400_000_000, swap_mode)).is_err());
+    }
+
+    // An empty output side is a shortfall too, while dust against a funded pool stays an error
+    let empty = self::amm(&pool_state(&[token_in, record(0, 4 * PONE / 5)], 3_000_000));
+    let empty_out = empty.get_reserve_mints()[1];
+    for swap_mode in [SwapMode::ExactIn, SwapMode::ExactOut] {
+        let params = QuoteParams {
+            amount: 1_000_000,
+            input_mint: token_in.mint_key,
+            output_mint: empty_out,
+            swap_mode,
+        };
+        assert!(empty.quote(&params).unwrap().not_enough_liquidity);
+    }
+    let deep_in = record(1_000_000_000_000, PONE / 2);
+    let dust = self::amm(&pool_state(&[deep_in, record(1_000_000, PONE / 2)], 0));
+    let error = dust.quote(&QuoteParams {
+        amount: 1,
+        input_mint: deep_in.mint_key,
+        output_mint: dust.get_reserve_mints()[1],
+        swap_mode: SwapMode::ExactIn,
+    });
+    assert_eq!(convert_anchor_error(&error.unwrap_err()), Some(ErrorCode::ValidationTooSmallTokenOutAmount));
+}